@@ -0,0 +1,103 @@
+use std::{
+    hint::black_box,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use web_server_rust::ThreadPool;
+
+const JOBS: usize = 1_000_000;
+const THREADS: usize = 4;
+
+// The way the ThreadPool used to hand out jobs, kept here so we have something to compare
+// against: one mpsc channel, with every Worker taking turns locking the same receiver
+struct MutexReceiverPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Box<dyn FnOnce() + Send + 'static>>>,
+}
+
+impl MutexReceiverPool {
+    fn new(num_threads: usize) -> MutexReceiverPool {
+        let (sender, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send + 'static>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..num_threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    let message = receiver.lock().unwrap().recv();
+                    match message {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        MutexReceiverPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Drop for MutexReceiverPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+// Both benchmarks submit a million trivial jobs and then wait for all of them to finish.
+// The pools are created outside of the timed part, but shutting them down is included,
+// since that's how the old pool waits for its queue to drain
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch_1m_jobs");
+    group.sample_size(10);
+
+    group.bench_function("work_stealing", |b| {
+        b.iter_batched(
+            || ThreadPool::new(THREADS),
+            |pool| {
+                for i in 0..JOBS {
+                    pool.execute(move || {
+                        black_box(i);
+                    })
+                    .unwrap();
+                }
+                pool.shutdown();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("mutex_receiver", |b| {
+        b.iter_batched(
+            || MutexReceiverPool::new(THREADS),
+            |pool| {
+                for i in 0..JOBS {
+                    pool.execute(move || {
+                        black_box(i);
+                    });
+                }
+                drop(pool);
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
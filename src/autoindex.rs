@@ -0,0 +1,103 @@
+use std::{fmt::Write as _, fs, io, path::Path};
+
+use crate::url;
+
+use crate::{http_date::format_http_date, response::Response, template::escape};
+
+// A page listing everything in a directory, for a directory without an index.html of its own,
+// with a link to each file (and each directory inside of it), along with its size and when it
+// was last changed:
+//    ../
+//    css/                   -           Wed, 14 Oct 2026 05:06:19 GMT
+//    notes.txt              4.9 KiB     Wed, 14 Oct 2026 05:06:19 GMT
+//
+// Directories come first, and then the files, each in order of their names. Anything starting
+// with a "." (i.e.: ".git", or ".env") is left out, since those are usually meant to be hidden.
+// The link back up to the parent directory is only there when it's still inside of root
+//
+// path is the request's path, which the links are made from, and dir is where it points
+// to on the disk
+pub fn list_directory(root: &Path, dir: &Path, path: &str) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        // An entry we can't get the details of (i.e.: a symlink to something that's gone)
+        // is still listed, just without them
+        let metadata = entry.metadata().ok();
+        let is_dir = metadata.as_ref().is_some_and(|metadata| metadata.is_dir());
+        entries.push((is_dir, name, metadata));
+    }
+    entries.sort_by(|(a_dir, a_name, _), (b_dir, b_name, _)| {
+        b_dir.cmp(a_dir).then_with(|| a_name.cmp(b_name))
+    });
+
+    // Every link is made from the request's path, so it works whether or not the path
+    // had a "/" on the end
+    let base = format!("{}/", path.trim_end_matches('/'));
+    let title = escape(&url::decode(&base).unwrap_or_else(|_| base.clone()));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {title}</title>\n</head>\n<body>\n<h1>Index of {title}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n"
+    );
+    if dir != root {
+        let parent = match base.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, _)) => format!("{parent}/"),
+            None => "/".to_string(),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">../</a></td><td></td><td></td></tr>",
+            escape(&parent)
+        );
+    }
+
+    for (is_dir, name, metadata) in &entries {
+        let slash = if *is_dir { "/" } else { "" };
+        let href = format!("{base}{}{slash}", url::encode(name));
+        let size = match metadata {
+            Some(metadata) if !is_dir => format_size(metadata.len()),
+            _ => "-".to_string(),
+        };
+        let modified = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified().ok())
+            .map_or_else(String::new, format_http_date);
+
+        // A file named something like "<script>alert(1)</script>.txt" shows up as just that,
+        // instead of running
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            escape(&href),
+            escape(name),
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    Ok(Response::ok()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(html))
+}
+
+// A size the way a person would write it, i.e.: "512 B", "4.9 KiB", or "1.3 GiB"
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
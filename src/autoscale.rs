@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use crate::ThreadPool;
+
+// How many samples in a row have to agree that the pool's too busy (or too idle) before it
+// gets resized, so a moment's burst (or lull) doesn't change anything
+const SUSTAINED_SAMPLES: u32 = 3;
+
+// When the pool gets grown or shrunk, from --autoscale and the options that go with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoscalePolicy {
+    // The fewest and the most Workers the pool can have
+    pub min: usize,
+    pub max: usize,
+    // The pool is saturated when every Worker is busy, and at least this many connections
+    // are waiting on one
+    pub queued: usize,
+    // How often to look at how busy the pool is
+    pub interval: Duration,
+    // How long to wait after resizing the pool before resizing it again, so it has the time
+    // to settle in at its new size, instead of going back and forth
+    pub cooldown: Duration,
+}
+
+// Keeps track of how busy the pool has been, and resizes it once it's been saturated (or
+// mostly idle) for long enough:
+//    saturated    -> grown by half, up to max
+//    mostly idle  -> shrunk by a quarter, down to min (at most half its Workers were busy,
+//                    and nothing was waiting on them)
+// It doesn't have a thread of its own, since resizing the pool takes the pool itself, so
+// whoever owns the pool (i.e.: the accept loop) calls tick every so often instead
+pub struct Autoscaler {
+    next_sample: Instant,
+    last_resize: Option<Instant>,
+    // How many samples in a row the pool has been saturated, or mostly idle
+    busy: u32,
+    idle: u32,
+}
+
+impl Autoscaler {
+    pub fn new() -> Autoscaler {
+        Autoscaler {
+            next_sample: Instant::now(),
+            last_resize: None,
+            busy: 0,
+            idle: 0,
+        }
+    }
+
+    // Takes a sample of how busy pool is, if it's been long enough since the last one, and
+    // resizes it if it's called for
+    pub fn tick(&mut self, pool: &mut ThreadPool, policy: &AutoscalePolicy) {
+        let now = Instant::now();
+        if now < self.next_sample {
+            return;
+        }
+        self.next_sample = now + policy.interval;
+
+        let size = pool.size();
+        let active = pool.active_count();
+        let queued = pool.queued_count();
+        if active >= size && queued >= policy.queued {
+            self.busy += 1;
+            self.idle = 0;
+        } else if active <= size / 2 && queued == 0 {
+            self.idle += 1;
+            self.busy = 0;
+        } else {
+            self.busy = 0;
+            self.idle = 0;
+        }
+
+        let cooling_down = self
+            .last_resize
+            .is_some_and(|resized| now.duration_since(resized) < policy.cooldown);
+        if cooling_down {
+            return;
+        }
+
+        // A pool that's outside of the limits (i.e.: since they were changed by a reload)
+        // gets brought back inside them straight away
+        let new_size = if size < policy.min || size > policy.max {
+            size.clamp(policy.min, policy.max)
+        } else if self.busy >= SUSTAINED_SAMPLES {
+            (size + (size / 2).max(1)).min(policy.max)
+        } else if self.idle >= SUSTAINED_SAMPLES {
+            (size - (size / 4).max(1)).max(policy.min)
+        } else {
+            return;
+        };
+        if new_size == size {
+            return;
+        }
+
+        if new_size > size {
+            eprintln!(
+                "Growing the pool from {size} to {new_size} workers, with {queued} connections waiting"
+            );
+        } else {
+            eprintln!("Shrinking the pool from {size} to {new_size} workers, with {active} busy");
+        }
+        pool.set_size(new_size);
+        self.last_resize = Some(now);
+        self.busy = 0;
+        self.idle = 0;
+    }
+}
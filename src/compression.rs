@@ -0,0 +1,216 @@
+use std::io::{self, Write};
+
+#[cfg(any(feature = "gzip", feature = "deflate"))]
+use flate2::Compression;
+
+use crate::{request::Request, response::Response};
+
+// Bodies smaller than this aren't worth compressing, since the encoding's header and the time
+// spent compressing cost more than the few bytes we'd save
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+// The encodings we can compress with, in the order we'd rather use them in when the client
+// likes more than one of them just as much. Each one is only built in with its own feature
+const ENCODINGS: &[Encoding] = &[
+    #[cfg(feature = "brotli")]
+    Encoding::Brotli,
+    #[cfg(feature = "gzip")]
+    Encoding::Gzip,
+    #[cfg(feature = "deflate")]
+    Encoding::Deflate,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+impl Encoding {
+    // The encoding's name, in Accept-Encoding and Content-Encoding
+    fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn compress(self, contents: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            // Brotli's best quality (11) is far too slow to do on every request, so this is
+            // the middle of the range, which still beats gzip on text
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(contents)?;
+                Ok(encoder.into_inner())
+            }
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents)?;
+                encoder.finish()
+            }
+            // "deflate" is zlib's format, rather than raw deflate, despite the name
+            #[cfg(feature = "deflate")]
+            Encoding::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(contents)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Compresses the response's body with whichever encoding the client would most like, out of
+// the ones we have, if it accepts any of them and compressing it is worthwhile. The
+// Content-Length gets worked out from the compressed body when the response is written,
+// so it doesn't need to be touched here, but an ETag does, since it was made for the bytes
+// before they were compressed
+pub fn compress_if_accepted(request: &Request, response: Response) -> Response {
+    let Some(encoding) = request
+        .header("Accept-Encoding")
+        .and_then(preferred_encoding)
+    else {
+        return response;
+    };
+    if !worth_compressing(&response) {
+        return response;
+    }
+    let Some(contents) = response.contents() else {
+        return response;
+    };
+
+    match encoding.compress(contents) {
+        Ok(compressed) => response
+            .weaken_etag()
+            .header("Content-Encoding", encoding.name())
+            .header("Vary", "Accept-Encoding")
+            .body(compressed),
+        // Compressing into memory shouldn't ever fail, but if it does,
+        // the client can still have the uncompressed body
+        Err(_) => response,
+    }
+}
+
+// Picks the encoding to use from an Accept-Encoding header, i.e.: "gzip;q=0.5, br;q=1.0" is br.
+// Each encoding can have a quality from 0 to 1 (without one, it's 1), and the one with the
+// highest quality wins. A quality of 0 means the client won't take that encoding at all, and
+// neither will it take any encoding it doesn't list, unless it has a "*" for everything else.
+// None means the client doesn't accept anything we have, so it gets the body as it is
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let qualities: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|encoding| {
+            let mut parts = encoding.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            // A quality we can't make sense of is taken as the client not wanting it
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            Some((name, quality))
+        })
+        .collect();
+    let quality_of = |name: &str| {
+        let listed = |wanted: &str| {
+            qualities
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|&(_, quality)| quality)
+        };
+        listed(name).or_else(|| listed("*")).unwrap_or(0.0)
+    };
+
+    // Ties go to whichever comes first in ENCODINGS, since max_by gives back the last of the
+    // encodings that are equal, and they're gone through in reverse
+    ENCODINGS
+        .iter()
+        .rev()
+        .map(|&encoding| (encoding, quality_of(encoding.name())))
+        .filter(|&(_, quality)| quality > 0.0)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(encoding, _)| encoding)
+}
+
+// Only bodies that are in memory, big enough, not already encoded, and of a kind that actually
+// gets smaller (text, mostly; images like PNGs and JPEGs are compressed already) get compressed.
+// Partial content is left alone too, since its Content-Range counts bytes of the file
+// as it is, not as it would be compressed
+fn worth_compressing(response: &Response) -> bool {
+    if response
+        .contents()
+        .is_none_or(|contents| contents.len() < MIN_COMPRESS_SIZE)
+        || response.header_value("Content-Encoding").is_some()
+        || response.header_value("Content-Length").is_some()
+        || response.header_value("Content-Range").is_some()
+    {
+        return false;
+    }
+
+    let content_type = response.header_value("Content-Type").unwrap_or_default();
+    content_type.starts_with("text/")
+        || [
+            "application/json",
+            "application/javascript",
+            "application/wasm",
+            "image/svg+xml",
+        ]
+        .iter()
+        .any(|compressible| content_type.starts_with(compressible))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "gzip", feature = "brotli"))]
+    #[test]
+    fn picks_the_encoding_with_the_highest_quality() {
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, br;q=1.0"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(preferred_encoding("gzip, br;q=0.9"), Some(Encoding::Gzip));
+    }
+
+    // Without brotli built in, "br" is just another encoding we don't have
+    #[cfg(all(feature = "gzip", not(feature = "brotli")))]
+    #[test]
+    fn passes_over_an_encoding_it_does_not_have() {
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, br;q=1.0"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(preferred_encoding("br"), None);
+    }
+
+    #[cfg(all(feature = "gzip", feature = "deflate"))]
+    #[test]
+    fn never_picks_an_encoding_with_a_quality_of_0() {
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, deflate;q=1.0"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            preferred_encoding("gzip;q=0, deflate;q=0.1"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            preferred_encoding("*;q=0.5, gzip;q=0, br;q=0"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(preferred_encoding("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(preferred_encoding("*;q=0"), None);
+        assert_eq!(preferred_encoding("identity"), None);
+    }
+}
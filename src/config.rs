@@ -0,0 +1,727 @@
+use std::{
+    env,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    autoscale::AutoscalePolicy, logger::LogFormat, middleware::Cors, request::RequestLimits,
+    router::TrailingSlash,
+};
+
+/// Everything about how the server runs that can be changed without recompiling it
+///
+/// Each setting starts out with a default, which can be overridden by a line in the config
+/// file (if there is one), then by an environment variable, and then by a command-line
+/// argument, i.e.:
+/// ```text
+/// WEB_SERVER_PORT=8080 web-server-rust --config server.conf --workers 8
+/// ```
+/// See OPTIONS for the full list
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The address to listen on, i.e.: "127.0.0.1", or "0.0.0.0" for every interface
+    pub host: String,
+    /// The port to listen on, or 0 for whichever one the OS picks
+    pub port: u16,
+    /// Every address to listen on, to serve on more than one interface, port, or IP version at
+    /// once, or on a Unix domain socket. If this is empty, we just listen on host and port
+    pub listen: Vec<Listen>,
+    /// The number of threads in the pool, which is the most connections we handle at once
+    pub workers: usize,
+    /// The directory that static files are served out of
+    pub static_root: PathBuf,
+    /// Whether a directory without an index.html gets a page listing what's in it. This is off
+    /// unless it's asked for, since it shows off every file, even ones nothing links to
+    pub autoindex: bool,
+    /// The file (inside of static_root) that's served for a GET to any path that doesn't have a
+    /// route or a file of its own, i.e.: "index.html", for a single-page app that does its own
+    /// routing, so "/settings/profile" still loads the app when it's typed in or reloaded
+    pub fallback: Option<PathBuf>,
+    /// Whether to serve the example key/value store at /kv/:key, where a PUT stores its body
+    /// under the key, a GET gives it back, and a DELETE removes it. It's off unless it's asked
+    /// for, since anyone who can reach the server can store things in it (up to a limit),
+    /// unless auth_token or basic_auth is set too
+    pub kv_store: bool,
+    /// How long we wait on a client to send us its request before giving up on it. Without
+    /// this, a client that connects and then never sends anything (or sends it a byte at a
+    /// time) could tie up one of the pool's threads for as long as it likes
+    pub read_timeout: Duration,
+    /// How long a connection that's already been served can sit there, without its client
+    /// sending another request on it, before we close it. A connection holds on to its Worker
+    /// the whole time it's open, so this is how long an idle client can keep one busy for
+    pub keep_alive_timeout: Duration,
+    /// The most requests a client can send over one connection before we close it
+    pub max_requests_per_connection: usize,
+    /// The most connections we'll have open at once. Any more than that get a 503 straight
+    /// away, instead of waiting in the pool's queue for who knows how long
+    pub max_connections: usize,
+    /// Whether each response goes out as soon as it's written (TCP_NODELAY), instead of the OS
+    /// holding on to a small one (i.e.: just the headers, or a small body after them) until the
+    /// client acknowledges what came before it, which can add 40ms or more to every response.
+    /// Holding on only helps programs that write a few bytes at a time, which we don't
+    pub tcp_nodelay: bool,
+    /// How many connections the OS keeps waiting for us to accept, before it starts turning
+    /// any more away, for when they come in faster than we accept them. The OS caps it at its
+    /// own limit (i.e.: net.core.somaxconn on Linux), and it can only be changed on Unix
+    pub listen_backlog: u32,
+    /// How big each part of a request can be
+    pub limits: RequestLimits,
+    /// If this is set, every request has to come with `Authorization: Bearer <token>`
+    pub auth_token: Option<String>,
+    /// If this is set, every request has to come with this username and password, using
+    /// HTTP Basic Authentication, i.e.: ("admin", "hunter2") for "--basic-auth admin:hunter2"
+    pub basic_auth: Option<(String, String)>,
+    /// Which other sites' scripts can call us. CORS is left off if there aren't any origins
+    pub cors: Cors,
+    /// The certificate and private key to serve HTTPS with (as PEM files). Without them, we
+    /// serve plain HTTP. They can only be used when the server is built with the "tls" feature
+    pub tls_cert: Option<PathBuf>,
+    /// The private key that goes with tls_cert
+    pub tls_key: Option<PathBuf>,
+    /// The most requests each client (by IP address) can send within a window of time, i.e.:
+    /// (100, 60 seconds) for "--rate-limit 100/60". If this isn't set, there's no limit
+    pub rate_limit: Option<(u32, Duration)>,
+    /// Whether we're behind a reverse proxy, which passes on the address of each request's
+    /// client in X-Forwarded-For (or X-Real-IP). Otherwise, those headers could have come from
+    /// anyone, and are ignored
+    pub trust_proxy: bool,
+    /// How long a session lasts after the last request that used it
+    pub session_ttl: Duration,
+    /// Where the request and thread pool counters are served, for Prometheus to collect
+    pub metrics_path: String,
+    /// Whether to also log things that are only useful when working out what's going on,
+    /// i.e.: clients that disconnect in the middle of a request
+    pub debug: bool,
+    /// How long to wait for the requests in progress to finish when shutting down, before
+    /// giving up on them
+    pub shutdown_timeout: Duration,
+    /// Whether a request for a path with (or without) a "/" on the end gets redirected to the
+    /// route without it (or with it), if that's the only one there is
+    pub trailing_slash: TrailingSlash,
+    /// How each line of the request log is laid out (see LogFormat)
+    pub log_format: LogFormat,
+    /// The file the request log goes to, which gets reopened on a SIGUSR1 (i.e.: once it's been
+    /// rotated). None for stdout
+    pub access_log: Option<PathBuf>,
+    /// How long a connection can wait for a Worker before it isn't worth handling anymore,
+    /// since its client will most likely have given up on it. None for waiting forever
+    pub queue_timeout: Option<Duration>,
+    /// A file with more options in it, which gets read again whenever the config gets reloaded
+    pub config_file: Option<PathBuf>,
+    /// Headers that go on every response, unless its handler has already set one with the same
+    /// name, i.e.: ("X-Content-Type-Options", "nosniff"). That's where security headers go, so
+    /// no handler can forget them. It starts out with just the Server header
+    pub default_headers: Vec<(String, String)>,
+    /// Once this many connections are waiting in the pool's queue, we stop accepting any more,
+    /// and leave them waiting in the OS's backlog instead, until the queue is back down to the
+    /// low-water mark (or half of this, without one). None for accepting everything
+    pub queue_high_water: Option<usize>,
+    /// How few connections there have to be queued again, once we've stopped accepting, before
+    /// we start again
+    pub queue_low_water: Option<usize>,
+    /// The fewest and the most Workers the pool can be scaled between, going by how busy it
+    /// is, i.e.: (2, 16) for "--autoscale 2-16". None for keeping it at the same size
+    pub autoscale: Option<(usize, usize)>,
+    /// How many connections have to be waiting on a saturated pool before it counts as too busy
+    pub autoscale_queued: usize,
+    /// How often to check how busy the pool is
+    pub autoscale_interval: Duration,
+    /// How long to leave the pool be after resizing it, before it can be resized again
+    pub autoscale_cooldown: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 7878,
+            listen: Vec::new(),
+            workers: 4,
+            static_root: PathBuf::from("pages"),
+            autoindex: false,
+            fallback: None,
+            kv_store: false,
+            read_timeout: Duration::from_secs(10),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests_per_connection: 100,
+            max_connections: 256,
+            tcp_nodelay: true,
+            listen_backlog: 128,
+            limits: RequestLimits::default(),
+            auth_token: None,
+            basic_auth: None,
+            cors: Cors::default(),
+            tls_cert: None,
+            tls_key: None,
+            rate_limit: None,
+            trust_proxy: false,
+            session_ttl: Duration::from_secs(30 * 60),
+            metrics_path: "/metrics".to_string(),
+            debug: false,
+            shutdown_timeout: Duration::from_secs(30),
+            trailing_slash: TrailingSlash::Off,
+            log_format: LogFormat::parse(LogFormat::COMBINED).unwrap(),
+            access_log: None,
+            queue_timeout: None,
+            config_file: None,
+            default_headers: vec![("Server".to_string(), SERVER.to_string())],
+            queue_high_water: None,
+            queue_low_water: None,
+            autoscale: None,
+            autoscale_queued: 1,
+            autoscale_interval: Duration::from_secs(1),
+            autoscale_cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+// Every setting, along with a description of it for the usage message. The command-line
+// argument is the name with "--" in front of it, and the environment variable is the
+// name in uppercase, with "WEB_SERVER_" in front of it (i.e.: --read-timeout is
+// WEB_SERVER_READ_TIMEOUT)
+const OPTIONS: &[(&str, &str)] = &[
+    ("host", "address to listen on [127.0.0.1]"),
+    ("port", "port to listen on [7878]"),
+    ("listen", "addresses, i.e. a:80,unix:/tmp/web.sock [none]"),
+    ("workers", "number of worker threads [4]"),
+    ("static-root", "directory of static files [pages]"),
+    (
+        "autoindex",
+        "list directories without an index.html [false]",
+    ),
+    ("fallback", "file to serve for unknown paths [none]"),
+    ("kv-store", "serve the example store at /kv/:key [false]"),
+    ("read-timeout", "seconds to wait for a request [10]"),
+    (
+        "keep-alive-timeout",
+        "seconds an idle connection stays open [5]",
+    ),
+    ("max-requests", "requests per connection [100]"),
+    ("max-connections", "connections open at once [256]"),
+    ("tcp-nodelay", "send responses without delay [true]"),
+    ("listen-backlog", "connections waiting to be accepted [128]"),
+    ("max-body-size", "max request body bytes [1048576]"),
+    ("max-header-size", "max request header bytes [8192]"),
+    ("max-request-line", "max request line bytes [4096]"),
+    ("auth-token", "require this Bearer token [none]"),
+    ("basic-auth", "require this user:password [none]"),
+    ("cors-origins", "origins allowed by CORS, or * [none]"),
+    ("cors-methods", "methods allowed by CORS [GET,HEAD,POST]"),
+    ("cors-headers", "headers allowed by CORS [Content-Type]"),
+    ("tls-cert", "certificate PEM file, for HTTPS [none]"),
+    ("tls-key", "private key PEM file, for HTTPS [none]"),
+    ("rate-limit", "requests/seconds per client IP [none]"),
+    ("trust-proxy", "use X-Forwarded-For as the client [false]"),
+    ("session-ttl", "seconds an unused session lasts [1800]"),
+    ("metrics-path", "path to serve metrics on [/metrics]"),
+    ("debug", "log details, i.e. dropped clients [false]"),
+    (
+        "shutdown-timeout",
+        "seconds to wait for requests on shutdown [30]",
+    ),
+    ("trailing-slash", "redirect to strip, append, or off [off]"),
+    (
+        "log-format",
+        "request log line, i.e. \"%h %r %s\" [combined]",
+    ),
+    ("access-log", "file to log requests to [stdout]"),
+    (
+        "queue-timeout",
+        "seconds a connection waits for a worker [none]",
+    ),
+    ("config", "file of options, i.e. port = 8080 [none]"),
+    (
+        "queue-high-water",
+        "queued connections to stop accepting at [none]",
+    ),
+    (
+        "queue-low-water",
+        "queued connections to accept again at [half]",
+    ),
+    (
+        "autoscale",
+        "min-max workers to scale the pool between [none]",
+    ),
+    (
+        "autoscale-queued",
+        "queued connections that make the pool busy [1]",
+    ),
+    (
+        "autoscale-interval",
+        "seconds between checks on the pool [1]",
+    ),
+    (
+        "autoscale-cooldown",
+        "seconds to leave the pool be after resizing [10]",
+    ),
+    (
+        "header",
+        "add to every response, i.e. \"X-Frame-Options: DENY\" [Server]",
+    ),
+];
+
+// What the Server header says we are, i.e.: "web-server-rust/0.1"
+const SERVER: &str = concat!(
+    env!("CARGO_PKG_NAME"),
+    "/",
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    ".",
+    env!("CARGO_PKG_VERSION_MINOR")
+);
+
+impl ServerConfig {
+    /// Builds the config from the defaults, the config file, the environment, and the
+    /// program's arguments
+    pub fn from_env_and_args() -> Result<ServerConfig, ConfigError> {
+        let mut config = ServerConfig::default();
+
+        let mut env_options = Vec::new();
+        for (name, _) in OPTIONS {
+            let variable = format!("WEB_SERVER_{}", name.to_uppercase().replace('-', "_"));
+            if let Ok(value) = env::var(&variable) {
+                env_options.push((name.to_string(), value));
+            }
+        }
+        let arg_options = ServerConfig::args()?;
+
+        // The file goes first, even though it's the environment or the arguments that say
+        // where it is, so that they can still override what's in it
+        let config_file = env_options
+            .iter()
+            .chain(&arg_options)
+            .rfind(|(name, _)| name == "config");
+        if let Some((_, path)) = config_file {
+            for (name, value) in ServerConfig::read_file(Path::new(path))? {
+                config.set(&name, &value)?;
+            }
+        }
+
+        for (name, value) in env_options.iter().chain(&arg_options) {
+            config.set(name, value)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Takes whatever can change while the server's running from new (a config that's just been
+    /// loaded again), and keeps the rest from this one. That's the options that would take new
+    /// listeners, a new thread pool, a new log file, or throwing away everyone's sessions. Gives
+    /// back the names of any of those that were changed, and have to wait for a restart
+    pub fn reload(&self, mut new: ServerConfig) -> (ServerConfig, Vec<&'static str>) {
+        fn keep<T: PartialEq + Clone>(
+            name: &'static str,
+            old: &T,
+            new: &mut T,
+            kept: &mut Vec<&'static str>,
+        ) {
+            if old != new {
+                kept.push(name);
+                *new = old.clone();
+            }
+        }
+
+        let mut kept = Vec::new();
+        keep("host", &self.host, &mut new.host, &mut kept);
+        keep("port", &self.port, &mut new.port, &mut kept);
+        keep("listen", &self.listen, &mut new.listen, &mut kept);
+        keep("workers", &self.workers, &mut new.workers, &mut kept);
+        keep(
+            "listen-backlog",
+            &self.listen_backlog,
+            &mut new.listen_backlog,
+            &mut kept,
+        );
+        keep("tls-cert", &self.tls_cert, &mut new.tls_cert, &mut kept);
+        keep("tls-key", &self.tls_key, &mut new.tls_key, &mut kept);
+        keep(
+            "access-log",
+            &self.access_log,
+            &mut new.access_log,
+            &mut kept,
+        );
+        keep(
+            "session-ttl",
+            &self.session_ttl,
+            &mut new.session_ttl,
+            &mut kept,
+        );
+        (new, kept)
+    }
+
+    // The options in a config file, which has one on each line, i.e.:
+    //    # Anything after a "#" at the start of a line is a comment
+    //    port = 8080
+    //    static-root = /srv/www
+    fn read_file(path: &Path) -> Result<Vec<(String, String)>, ConfigError> {
+        let unreadable = |reason: String| ConfigError::File {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let contents = fs::read_to_string(path).map_err(|err| unreadable(err.to_string()))?;
+
+        let mut options = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| unreadable(format!("line {} has no \"=\" in it", number + 1)))?;
+            options.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(options)
+    }
+
+    // The options from the program's arguments, which are either "--port 8080" or "--port=8080"
+    fn args() -> Result<Vec<(String, String)>, ConfigError> {
+        let mut options = Vec::new();
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                return Err(ConfigError::UnknownOption(arg));
+            };
+
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => match args.next() {
+                    Some(value) => (option.to_string(), value),
+                    None => return Err(ConfigError::MissingValue(arg)),
+                },
+            };
+            options.push((name, value));
+        }
+
+        Ok(options)
+    }
+
+    /// Whether the program was asked for its usage message, instead of being asked to run
+    pub fn help_requested() -> bool {
+        env::args()
+            .skip(1)
+            .any(|arg| arg == "--help" || arg == "-h")
+    }
+
+    /// Explains each of the options, for --help or when one of them is wrong
+    pub fn usage() -> String {
+        let mut usage =
+            String::from("Usage: web-server-rust [OPTIONS]\n\nOptions (defaults in brackets):\n");
+        for (name, description) in OPTIONS {
+            usage.push_str(&format!("    --{name:<20}{description}\n"));
+        }
+        usage.push_str("\nEach option can also be set with a WEB_SERVER_ environment variable,\n");
+        usage.push_str("i.e.: WEB_SERVER_PORT=8080 for --port 8080\n");
+        usage.push_str(
+            "\nOn Unix, TCP ports are always listened on with SO_REUSEADDR, so a server\n",
+        );
+        usage.push_str("that's restarted can listen on the same port again straight away\n");
+        usage
+    }
+
+    /// Everywhere the Listeners should listen on, i.e.: [Listen::Tcp("127.0.0.1:7878")]
+    pub fn bind_addresses(&self) -> Vec<Listen> {
+        if self.listen.is_empty() {
+            vec![Listen::Tcp(self.bind_address())]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    // The address made from host and port. IPv6 addresses have to be wrapped in brackets,
+    // so their colons don't get mixed up with the port's
+    fn bind_address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// The queued connections to stop accepting at, and then to start again at, if there's a
+    /// high-water mark. The low-water mark can't be any higher than the high-water mark, or
+    /// we'd never stop
+    pub fn queue_water_marks(&self) -> Option<(usize, usize)> {
+        let high = self.queue_high_water?;
+        let low = self.queue_low_water.unwrap_or(high / 2).min(high);
+        Some((high, low))
+    }
+
+    /// The policy for scaling the pool with, if there's one
+    pub fn autoscale_policy(&self) -> Option<AutoscalePolicy> {
+        let (min, max) = self.autoscale?;
+        Some(AutoscalePolicy {
+            min,
+            max,
+            queued: self.autoscale_queued,
+            interval: self.autoscale_interval,
+            cooldown: self.autoscale_cooldown,
+        })
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue {
+            option: name.to_string(),
+            value: value.to_string(),
+        };
+
+        match name {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "listen" => {
+                self.listen = list(value)
+                    .iter()
+                    .map(|listen| Listen::parse(listen))
+                    .collect()
+            }
+            "workers" => self.workers = value.parse().map_err(|_| invalid())?,
+            "static-root" => self.static_root = PathBuf::from(value),
+            "autoindex" => self.autoindex = value.parse().map_err(|_| invalid())?,
+            "kv-store" => self.kv_store = value.parse().map_err(|_| invalid())?,
+            "fallback" => self.fallback = Some(PathBuf::from(value)),
+            "read-timeout" => {
+                self.read_timeout = Duration::from_secs(value.parse().map_err(|_| invalid())?)
+            }
+            // A connection that's closed straight away would never get another request
+            "keep-alive-timeout" => {
+                self.keep_alive_timeout = value
+                    .parse()
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(invalid)?
+            }
+            // A connection has to be allowed at least one request, and the server at least one
+            // connection, or they'd be no use at all
+            "max-requests" => {
+                self.max_requests_per_connection = value
+                    .parse()
+                    .ok()
+                    .filter(|&max| max > 0)
+                    .ok_or_else(invalid)?
+            }
+            "max-connections" => {
+                self.max_connections = value
+                    .parse()
+                    .ok()
+                    .filter(|&max| max > 0)
+                    .ok_or_else(invalid)?
+            }
+            "tcp-nodelay" => self.tcp_nodelay = value.parse().map_err(|_| invalid())?,
+            "listen-backlog" => {
+                self.listen_backlog = value
+                    .parse()
+                    .ok()
+                    .filter(|&backlog| backlog > 0)
+                    .ok_or_else(invalid)?
+            }
+            "max-body-size" => self.limits.max_body_size = value.parse().map_err(|_| invalid())?,
+            "max-header-size" => {
+                self.limits.max_header_size = value.parse().map_err(|_| invalid())?
+            }
+            "max-request-line" => {
+                self.limits.max_request_line = value.parse().map_err(|_| invalid())?
+            }
+            "auth-token" => self.auth_token = Some(value.to_string()),
+            "basic-auth" => {
+                let (username, password) = value.split_once(':').ok_or_else(invalid)?;
+                self.basic_auth = Some((username.to_string(), password.to_string()));
+            }
+            // These are lists too, i.e.: "--cors-origins https://a.com,https://b.com"
+            "cors-origins" => self.cors.origins = list(value),
+            "cors-methods" => self.cors.methods = list(value),
+            "cors-headers" => self.cors.headers = list(value),
+            "tls-cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls-key" => self.tls_key = Some(PathBuf::from(value)),
+            // Neither the number of requests nor the window can be 0
+            "rate-limit" => {
+                let (requests, seconds) = value.split_once('/').ok_or_else(invalid)?;
+                let requests = requests.parse().ok().filter(|&max| max > 0);
+                let seconds = seconds.parse().ok().filter(|&window| window > 0);
+                let (Some(requests), Some(seconds)) = (requests, seconds) else {
+                    return Err(invalid());
+                };
+                self.rate_limit = Some((requests, Duration::from_secs(seconds)));
+            }
+            "trust-proxy" => self.trust_proxy = value.parse().map_err(|_| invalid())?,
+            // A session that expires straight away would never get used
+            "session-ttl" => {
+                self.session_ttl = value
+                    .parse()
+                    .ok()
+                    .filter(|&ttl| ttl > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(invalid)?
+            }
+            // Every path a request can ask for starts with a /, so one without it would never
+            // be served
+            "metrics-path" if value.starts_with('/') => self.metrics_path = value.to_string(),
+            "metrics-path" => return Err(invalid()),
+            "debug" => self.debug = value.parse().map_err(|_| invalid())?,
+            // 0 is allowed, for not waiting on anything at all
+            "shutdown-timeout" => {
+                self.shutdown_timeout = value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| invalid())?
+            }
+            // A connection that expires straight away would never get handled
+            "queue-timeout" => {
+                self.queue_timeout = value
+                    .parse()
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .map(Duration::from_secs)
+                    .map(Some)
+                    .ok_or_else(invalid)?
+            }
+            // The queue has to fill up with at least one connection before we stop accepting
+            "queue-high-water" => {
+                self.queue_high_water = value
+                    .parse()
+                    .ok()
+                    .filter(|&high| high > 0)
+                    .map(Some)
+                    .ok_or_else(invalid)?
+            }
+            "queue-low-water" => self.queue_low_water = Some(value.parse().map_err(|_| invalid())?),
+            // The pool needs at least one Worker, and can't have fewer at most than at least
+            "autoscale" => {
+                let (min, max) = value.split_once('-').ok_or_else(invalid)?;
+                let min = min.parse().ok().filter(|&min| min > 0);
+                let max = max.parse().ok();
+                let (Some(min), Some(max)) = (min, max) else {
+                    return Err(invalid());
+                };
+                if max < min {
+                    return Err(invalid());
+                }
+                self.autoscale = Some((min, max));
+            }
+            "autoscale-queued" => self.autoscale_queued = value.parse().map_err(|_| invalid())?,
+            // Checking on the pool all the time would just keep the accept loop busy
+            "autoscale-interval" => {
+                self.autoscale_interval = value
+                    .parse()
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(invalid)?
+            }
+            "autoscale-cooldown" => {
+                self.autoscale_cooldown = value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| invalid())?
+            }
+            "config" => self.config_file = Some(PathBuf::from(value)),
+            "access-log" => self.access_log = Some(PathBuf::from(value)),
+            // The error is just the directive that's wrong, rather than the whole format
+            "log-format" => {
+                self.log_format =
+                    LogFormat::parse(value).map_err(|directive| ConfigError::InvalidValue {
+                        option: name.to_string(),
+                        value: directive,
+                    })?
+            }
+            "trailing-slash" => {
+                self.trailing_slash = match value {
+                    "strip" => TrailingSlash::Strip,
+                    "append" => TrailingSlash::Append,
+                    "off" => TrailingSlash::Off,
+                    _ => return Err(invalid()),
+                }
+            }
+            // This one can be given more than once, with a header each time. Giving one that's
+            // already there (like the Server header) replaces it, and giving it without a
+            // value (i.e.: "Server:") leaves it out altogether
+            "header" => {
+                let (header, header_value) = value.split_once(':').ok_or_else(invalid)?;
+                let (header, header_value) = (header.trim(), header_value.trim());
+                if header.is_empty() {
+                    return Err(invalid());
+                }
+                self.default_headers
+                    .retain(|(name, _)| !name.eq_ignore_ascii_case(header));
+                if !header_value.is_empty() {
+                    self.default_headers
+                        .push((header.to_string(), header_value.to_string()));
+                }
+            }
+            _ => return Err(ConfigError::UnknownOption(format!("--{name}"))),
+        }
+
+        Ok(())
+    }
+}
+
+/// Somewhere to listen for connections on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    /// A TCP address, i.e.: "127.0.0.1:7878", or `[::1]:7878`
+    Tcp(String),
+    /// The path of a Unix domain socket, written with "unix:" in front of it,
+    /// i.e.: "unix:/tmp/web-server.sock"
+    Unix(PathBuf),
+}
+
+impl Listen {
+    fn parse(value: &str) -> Listen {
+        match value.strip_prefix("unix:") {
+            Some(path) => Listen::Unix(PathBuf::from(path)),
+            None => Listen::Tcp(value.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(address) => f.write_str(address),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Splits a comma-separated list, leaving out any empty entries
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The ways the config from the environment or the arguments can be wrong
+#[derive(Debug)]
+pub enum ConfigError {
+    /// An argument that isn't one of the options
+    UnknownOption(String),
+    /// An option at the very end of the arguments, with nothing after it
+    MissingValue(String),
+    /// An option whose value doesn't make sense for it, i.e.: "--port lots"
+    InvalidValue { option: String, value: String },
+    /// A config file that couldn't be read, or has a line in it that isn't an option
+    File { path: PathBuf, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnknownOption(option) => write!(f, "unknown option: {option}"),
+            ConfigError::MissingValue(option) => write!(f, "{option} needs a value"),
+            ConfigError::InvalidValue { option, value } => {
+                write!(f, "invalid value for --{option}: {value:?}")
+            }
+            ConfigError::File { path, reason } => {
+                write!(f, "unable to read {}: {reason}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
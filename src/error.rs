@@ -0,0 +1,128 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use crate::PoolCreationError;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsError;
+use crate::{config::Listen, request::ParseError, response::Response};
+
+/// The ways handling a connection can go wrong
+#[derive(Debug)]
+pub enum ServerError {
+    /// The client sent something we couldn't make sense of
+    Parse(ParseError),
+    /// Talking to the client failed, i.e.: it went away before we could respond
+    Io(io::Error),
+}
+
+impl ServerError {
+    /// What to tell the client about the error: whatever status the ParseError calls for if
+    /// it's something wrong with the request (see ParseError::status), and 500 when the
+    /// problem is on our end
+    pub fn response(&self) -> Response {
+        match self {
+            ServerError::Parse(err) => {
+                let (code, reason) = err.status();
+                Response::ok().status(code, reason)
+            }
+            ServerError::Io(_) => Response::ok().status(500, "INTERNAL SERVER ERROR"),
+        }
+    }
+
+    /// Whether the error just means the client went away partway through a request or a
+    /// response, i.e.: someone closing their browser tab while a page was still loading.
+    /// That happens all the time, isn't anything wrong with the server, and there's nobody
+    /// left to send an error response to
+    pub fn is_disconnect(&self) -> bool {
+        let err = match self {
+            ServerError::Parse(ParseError::UnexpectedEof) => return true,
+            ServerError::Parse(ParseError::Io(err)) | ServerError::Io(err) => err,
+            ServerError::Parse(_) => return false,
+        };
+
+        matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::UnexpectedEof
+        )
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Parse(err) => write!(f, "bad request: {err}"),
+            ServerError::Io(err) => write!(f, "connection error: {err}"),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Parse(err) => Some(err),
+            ServerError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<ParseError> for ServerError {
+    fn from(err: ParseError) -> ServerError {
+        ServerError::Parse(err)
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> ServerError {
+        ServerError::Io(err)
+    }
+}
+
+/// The ways starting the server can go wrong, before it's served anything
+#[derive(Debug)]
+pub enum StartError {
+    /// We couldn't listen on one of the addresses, i.e.: its port is already in use
+    Bind(Listen, io::Error),
+    /// The options don't work together, i.e.: --tls-cert without --tls-key
+    Config(String),
+    /// The certificate or private key couldn't be used
+    #[cfg(feature = "tls")]
+    Tls(TlsError),
+    /// The thread pool couldn't be started, i.e.: with 0 workers
+    Pool(PoolCreationError),
+    /// The access log's file couldn't be opened, i.e.: its directory doesn't exist
+    AccessLog(PathBuf, io::Error),
+    /// Anything else the OS wouldn't let us do, i.e.: start the thread that accepts connections
+    Io(io::Error),
+}
+
+impl StartError {
+    /// What to exit with: 2 when the options were wrong, the same as for an option we don't
+    /// know, and 1 for anything else
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StartError::Config(_) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Bind(address, err) => write!(f, "Unable to listen on {address}: {err}"),
+            StartError::Config(reason) => f.write_str(reason),
+            #[cfg(feature = "tls")]
+            StartError::Tls(err) => write!(f, "Unable to set up TLS: {err}"),
+            StartError::Pool(err) => write!(f, "Unable to start the thread pool: {err}"),
+            StartError::AccessLog(path, err) => {
+                write!(f, "Unable to open the access log {}: {err}", path.display())
+            }
+            StartError::Io(err) => write!(f, "Unable to start the server: {err}"),
+        }
+    }
+}
+
+impl Error for StartError {}
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{response::Response, static_files::mime_type_for};
+
+// What to show the client for one status code
+enum ErrorPage {
+    // The contents of a file, i.e.: "pages/404.html"
+    File(PathBuf),
+    // Whatever the closure makes out of the bare error response (which still has its status,
+    // and any headers that came with it, like the Allow header on a 405)
+    Handler(Box<dyn Fn(Response) -> Response + Send + Sync>),
+}
+
+/// Fills in the body of error responses (anything 400 or above), so a handler only has to
+/// pick the status, and every 404 looks the same no matter which handler it came from.
+/// Codes without a page of their own get a plain-text body, i.e.: "404 NOT FOUND"
+#[derive(Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, ErrorPage>,
+}
+
+impl ErrorPages {
+    /// Error pages without any pages of their own, so every error gets a plain-text body
+    pub fn new() -> ErrorPages {
+        ErrorPages::default()
+    }
+
+    /// Serves the file at path for responses with the given status
+    pub fn file(&mut self, status: u16, path: impl AsRef<Path>) {
+        self.pages
+            .insert(status, ErrorPage::File(path.as_ref().to_path_buf()));
+    }
+
+    /// Lets handler build the response for the given status. Our own pages are all files,
+    /// so this is only here for anyone who needs something more than that
+    pub fn handler<F>(&mut self, status: u16, handler: F)
+    where
+        F: Fn(Response) -> Response + Send + Sync + 'static,
+    {
+        self.pages
+            .insert(status, ErrorPage::Handler(Box::new(handler)));
+    }
+
+    /// Gives the response its error page, if it's an error and doesn't already have a body.
+    /// Anything else is passed through as it is
+    pub fn render(&self, response: Response) -> Response {
+        let status = response.status_code();
+        if status < 400 || response.body_len() != Some(0) {
+            return response;
+        }
+
+        match self.pages.get(&status) {
+            Some(ErrorPage::Handler(handler)) => handler(response),
+            Some(ErrorPage::File(path)) => match fs::read(path) {
+                Ok(contents) => response
+                    .header("Content-Type", mime_type_for(path))
+                    .body(contents),
+                // The page going missing shouldn't leave the client with nothing at all
+                Err(err) => {
+                    eprintln!("Unable to read {}: {err}", path.display());
+                    plain_text(response)
+                }
+            },
+            None => plain_text(response),
+        }
+    }
+}
+
+fn plain_text(response: Response) -> Response {
+    let body = format!("{} {}\n", response.status_code(), response.reason());
+    response
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(body)
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    request::{HttpMethod, Request},
+    response::Response,
+    static_files::{serve_file, serve_static},
+    Spawner,
+};
+
+// How long /stream waits for its background job to start, before doing the work itself
+const STREAM_START_TIMEOUT: Duration = Duration::from_millis(500);
+
+// The handlers our Router sends requests to. Each one renders a simple HTML page by
+// reading in the contents of an HTML page, and uses those as the body of the response
+
+// Says hello from whoever the request asks for, i.e.: "/?name=Ferris", or from Rust
+pub fn hello(request: &Request) -> Response {
+    let name = request.query("name").unwrap_or("Rust");
+    Response::html_template(
+        "pages/hello.html",
+        &HashMap::from([("name", name.to_string())]),
+    )
+}
+
+// Waits for 5 seconds before responding, or fewer if the request asks for it,
+// i.e.: "/sleep?seconds=2"
+pub fn sleep(request: &Request) -> Response {
+    let seconds = request
+        .query("seconds")
+        .and_then(|seconds| seconds.parse().ok())
+        .map_or(5, |seconds: u64| seconds.min(5));
+
+    thread::sleep(Duration::from_secs(seconds));
+    hello(request)
+}
+
+// Lets anything keeping an eye on the server check that it's up, and how busy it is,
+// i.e.: {"status":"ok","connections":3}
+#[cfg(feature = "json")]
+pub fn status(_request: &Request, connections: &AtomicUsize) -> Response {
+    Response::json(&serde_json::json!({
+        "status": "ok",
+        "connections": connections.load(Ordering::SeqCst),
+    }))
+}
+
+// Counts down to zero, one line a second, from 5 or however many seconds the request asks for
+// (up to 5), i.e.: "/countdown?from=3". We don't know how long the body is until it's done,
+// so it gets sent a chunk at a time, with each line going out as soon as it's ready
+pub fn countdown(request: &Request) -> Response {
+    let from = request
+        .query("from")
+        .and_then(|from| from.parse().ok())
+        .map_or(5, |from: u64| from.min(5));
+
+    let lines = (0..=from).rev().map(move |n| {
+        if n != from {
+            thread::sleep(Duration::from_secs(1));
+        }
+        format!("{n}\n").into_bytes()
+    });
+
+    Response::ok()
+        .header("Content-Type", "text/plain")
+        .body_chunks(lines)
+}
+
+// Finds the first 1000 primes, or however many the request asks for (up to 100,000), i.e.:
+// "/stream?primes=50", and sends them back a line at a time as they're found. The finding is
+// done by a job of its own, on another of the pool's Workers, while this one sends each batch
+// of them on as soon as it comes back
+//
+// If every Worker is busy with a connection, the job won't start until one of them is free,
+// and if they're all waiting on jobs like this one, that would be never. So if the job hasn't
+// started in time, we find the primes ourselves instead (all at once), and the job does
+// nothing when it does get to run. The same goes for a job the pool turned down, i.e.:
+// because its queue was full
+pub fn stream(request: &Request, spawner: &Spawner) -> Response {
+    let count = request
+        .query("primes")
+        .and_then(|count| count.parse().ok())
+        .map_or(1000, |count: usize| count.min(100_000));
+
+    // Whichever of us gets to the work first does it
+    let claimed = Arc::new(AtomicBool::new(false));
+    let (sender, mut receiver) = mpsc::channel();
+    {
+        // A job that's turned down gets dropped, and so does its sender, which is how the
+        // loop below finds out it's never going to run
+        let claimed = Arc::clone(&claimed);
+        let _ = spawner.try_execute(move || {
+            if !claimed.swap(true, Ordering::SeqCst) {
+                find_primes(count, &sender);
+            }
+        });
+    }
+
+    let mut started = false;
+    let chunks = std::iter::from_fn(move || loop {
+        if started {
+            return receiver.recv().ok();
+        }
+
+        match receiver.recv_timeout(STREAM_START_TIMEOUT) {
+            Ok(chunk) => {
+                started = true;
+                return Some(chunk);
+            }
+            // The job hasn't started in time, or it's been dropped without running. The
+            // primes all go into a channel of our own, which we then take them back out of,
+            // the same as if they'd come from the job
+            Err(_) if !claimed.swap(true, Ordering::SeqCst) => {
+                let (sender, primes) = mpsc::channel();
+                find_primes(count, &sender);
+                receiver = primes;
+                started = true;
+            }
+            // The job ran, and it's already sent everything it found
+            Err(RecvTimeoutError::Disconnected) => return None,
+            // The job has started, it just hasn't found enough primes to send any yet
+            Err(RecvTimeoutError::Timeout) => started = true,
+        }
+    });
+
+    // How many primes were sent goes in a trailer, once they all have been
+    let sent = Arc::new(AtomicUsize::new(0));
+    let counted = Arc::clone(&sent);
+    let chunks = chunks.inspect(move |chunk| {
+        let primes = chunk.iter().filter(|&&byte| byte == b'\n').count();
+        counted.fetch_add(primes, Ordering::SeqCst);
+    });
+
+    Response::ok()
+        .header("Content-Type", "text/plain")
+        .body_chunks(chunks)
+        .trailers(&["X-Prime-Count"], move || {
+            let count = sent.load(Ordering::SeqCst);
+            vec![("X-Prime-Count".to_string(), count.to_string())]
+        })
+}
+
+// Sends the first count primes through sender, 100 at a time, stopping early if whoever's
+// receiving them has gone away
+fn find_primes(count: usize, sender: &Sender<Vec<u8>>) {
+    let mut primes: Vec<u64> = Vec::with_capacity(count);
+    let mut batch = String::new();
+    let mut candidate = 2;
+
+    while primes.len() < count {
+        let is_prime = primes
+            .iter()
+            .take_while(|&&prime| prime * prime <= candidate)
+            .all(|&prime| candidate % prime != 0);
+        if is_prime {
+            primes.push(candidate);
+            batch.push_str(&format!("{candidate}\n"));
+
+            let batch_done = primes.len().is_multiple_of(100) || primes.len() == count;
+            if batch_done
+                && sender
+                    .send(std::mem::take(&mut batch).into_bytes())
+                    .is_err()
+            {
+                return;
+            }
+        }
+        candidate += 1;
+    }
+}
+
+// Counts how many bytes were uploaded, i.e.: "Received 1048576 bytes". The body is read as it
+// comes in, and thrown away, so an upload can be much bigger than max-body-size
+pub fn upload(request: &Request) -> Response {
+    match io::copy(&mut *request.body_reader(), &mut io::sink()) {
+        Ok(received) => Response::ok()
+            .header("Content-Type", "text/plain")
+            .body(format!("Received {received} bytes\n")),
+        // The client hung up before it sent all of it, so it won't see this anyway
+        Err(_) => Response::ok().status(400, "BAD REQUEST"),
+    }
+}
+
+// Anything without a route of its own might be one of the files in the static root directory.
+// If it isn't, the client gets our 404 page
+pub fn static_file(root: &Path, autoindex: bool, request: &Request) -> Response {
+    if matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+        let response = serve_static(root, request, autoindex);
+        if response.status_code() != 404 {
+            return response;
+        }
+    }
+
+    not_found(request)
+}
+
+// The same file for every GET (or HEAD) request that gets this far, whatever its path, for
+// the Router's fallback. Any other method still gets a 404
+pub fn fallback_file(path: &Path, request: &Request) -> Response {
+    if matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+        return serve_file(path, request);
+    }
+
+    not_found(request)
+}
+
+// The Router's error pages fill in the body
+pub fn not_found(_request: &Request) -> Response {
+    Response::ok().status(404, "NOT FOUND")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        request::{parse_request, RequestLimits},
+        ThreadPool,
+    };
+
+    #[test]
+    fn finds_the_primes_itself_when_the_pool_turns_its_job_down() {
+        // The pool's one Worker is kept busy, and there's no queue for the job to wait in
+        let pool = ThreadPool::builder()
+            .num_threads(1)
+            .queue_capacity(0)
+            .build()
+            .unwrap();
+        let (started, busy) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = released.recv();
+        })
+        .unwrap();
+        busy.recv().unwrap();
+
+        let mut reader = Cursor::new(&b"GET /stream?primes=5 HTTP/1.1\r\n\r\n"[..]);
+        let request = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        let mut sent = Vec::new();
+        stream(&request, &pool.spawner())
+            .write_to(&mut sent)
+            .unwrap();
+        release.send(()).unwrap();
+
+        let sent = String::from_utf8(sent).unwrap();
+        assert!(
+            sent.ends_with("\r\n\r\nB\r\n2\n3\n5\n7\n11\n\r\n0\r\nX-Prime-Count: 5\r\n\r\n"),
+            "{sent:?}"
+        );
+    }
+}
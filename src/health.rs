@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{request::Request, response::Response};
+
+// What something keeping an eye on the server (i.e.: a container orchestrator, or a load
+// balancer) can ask it about how it's doing:
+//    /healthz -> whether it's running at all, which it must be if it can answer
+//    /readyz  -> whether it's finished starting up, and can be sent real requests
+//
+// A server that's draining (i.e.: before it's replaced in a rolling deploy) is never ready
+// again, since it's finishing off the connections it's already got, and won't take any more
+pub struct ServerState {
+    ready: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl ServerState {
+    // Starts out not ready, until set_ready is called
+    pub fn new() -> ServerState {
+        ServerState {
+            ready: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst) && !self.is_draining()
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn healthz(&self, _request: &Request) -> Response {
+        Response::ok()
+            .header("Content-Type", "text/plain")
+            .body("ok\n")
+    }
+
+    pub fn readyz(&self, _request: &Request) -> Response {
+        if self.is_ready() {
+            Response::ok()
+                .header("Content-Type", "text/plain")
+                .body("ready\n")
+        } else {
+            Response::ok()
+                .status(503, "SERVICE UNAVAILABLE")
+                .header("Content-Type", "text/plain")
+                .body("not ready\n")
+        }
+    }
+}
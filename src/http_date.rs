@@ -0,0 +1,153 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Dates in HTTP headers (like Last-Modified) are always written the same way, in GMT:
+//    "Sun, 06 Nov 1994 08:49:37 GMT"
+// which is the format from RFC 1123. Every date we send is in it, and it's the only one we
+// read back, since it's the only one anything still sends
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Writes out time as an HTTP date. Anything smaller than a second is dropped, since the
+// format can't hold it. A time from before 1970 is written as 1970 itself
+pub fn format_http_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = seconds / SECONDS_PER_DAY;
+    let seconds = seconds % SECONDS_PER_DAY;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        // The 1st of January, 1970 (day 0) was a Thursday
+        DAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+// Writes out time the way access logs do, i.e.: "06/Nov/1994:08:49:37 +0000", which is
+// always in UTC here, the same as the dates in headers
+pub fn format_log_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+    let seconds = seconds % SECONDS_PER_DAY;
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+// Reads an HTTP date back into a time, or None if it isn't one
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    // The day of the week doesn't tell us anything the rest of the date doesn't
+    let (_, date) = date.trim().split_once(", ")?;
+    let mut parts = date.split(' ');
+    let (Some(day), Some(month), Some(year), Some(time), Some("GMT"), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+
+    let day: u64 = parse_digits(day, 2)?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = parse_digits(year, 4)?;
+    let mut time = time.split(':');
+    let (Some(hour), Some(minute), Some(second), None) =
+        (time.next(), time.next(), time.next(), time.next())
+    else {
+        return None;
+    };
+    let (hour, minute, second) = (
+        parse_digits(hour, 2)?,
+        parse_digits(minute, 2)?,
+        parse_digits(second, 2)?,
+    );
+
+    if year < 1970 || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    // 60 seconds is allowed, for a leap second
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+// Parses a number that has to be written with exactly len digits, i.e.: "06" for the 6th
+fn parse_digits(digits: &str, len: usize) -> Option<u64> {
+    if digits.len() != len || !digits.bytes().all(|digit| digit.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        // Every 4th year is a leap year, except for every 100th, except for every 400th
+        2 if year.is_multiple_of(4) && !year.is_multiple_of(100) => 29,
+        2 if year.is_multiple_of(400) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Converting between a number of days since 1970 and a (year, month, day) is done the way
+// Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms" does it. The trick is to start
+// each year in March, so the leap day is always the very last day of the year, and to work
+// in eras of 400 years, since the calendar repeats itself exactly every 400 years
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch from 1970-01-01 back to 0000-03-01
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // March is 0, so January and February are 10 and 11, at the end of the year
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // January and February belong to the year before, since years start in March here
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
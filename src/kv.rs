@@ -0,0 +1,90 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{request::Request, response::Response};
+
+// The most keys the store holds, and the most bytes its keys and values can add up to, so
+// nobody can run the server out of memory by storing more and more in it
+const MAX_KEYS: usize = 1024;
+const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+// A place to keep values by their keys, for as long as the server's running:
+//    PUT /kv/greeting     -> stores the body under "greeting"
+//    GET /kv/greeting     -> gives back what was stored
+//    DELETE /kv/greeting  -> removes it
+// Every Worker shares the same store, and the Mutex makes sure only one of them changes it
+// at a time, so two PUTs for the same key at once leave one value or the other, never a mix
+//
+// It's only there when it's asked for (see ServerConfig::kv_store), since anyone who can
+// reach the server can store things in it, unless there's an auth middleware in front of it
+pub struct KeyValueStore {
+    values: Mutex<Values>,
+}
+
+struct Values {
+    values: HashMap<String, Vec<u8>>,
+    // How many bytes the keys and values add up to
+    bytes: usize,
+}
+
+impl KeyValueStore {
+    pub fn new() -> KeyValueStore {
+        KeyValueStore {
+            values: Mutex::new(Values {
+                values: HashMap::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    // A key that wasn't there before gets a "201 Created", and one that's been replaced
+    // gets a "204 No Content". Once the store is full, nothing more goes in it, and it's a
+    // "507 Insufficient Storage" instead
+    pub fn put(&self, request: &Request) -> Response {
+        let key = key(request);
+        let mut values = self.values.lock().unwrap();
+
+        let replacing = values.values.get(&key).map(|value| key.len() + value.len());
+        let bytes = values.bytes - replacing.unwrap_or(0) + key.len() + request.body.len();
+        if (replacing.is_none() && values.values.len() >= MAX_KEYS) || bytes > MAX_BYTES {
+            return Response::ok().status(507, "INSUFFICIENT STORAGE");
+        }
+
+        values.bytes = bytes;
+        values.values.insert(key, request.body.clone());
+        if replacing.is_some() {
+            Response::ok().status(204, "NO CONTENT")
+        } else {
+            Response::ok().status(201, "CREATED")
+        }
+    }
+
+    // A value could be anything at all, i.e.: a page with a script in it, which a browser
+    // would run as if it came from us if it were sent back as HTML. So it always goes out
+    // as plain bytes, with nosniff to keep the browser from guessing otherwise
+    pub fn get(&self, request: &Request) -> Response {
+        match self.values.lock().unwrap().values.get(&key(request)) {
+            Some(value) => Response::ok()
+                .header("Content-Type", "application/octet-stream")
+                .header("X-Content-Type-Options", "nosniff")
+                .body(value.clone()),
+            None => Response::ok().status(404, "NOT FOUND"),
+        }
+    }
+
+    pub fn delete(&self, request: &Request) -> Response {
+        let key = key(request);
+        let mut values = self.values.lock().unwrap();
+        match values.values.remove(&key) {
+            Some(value) => {
+                values.bytes -= key.len() + value.len();
+                Response::ok().status(204, "NO CONTENT")
+            }
+            None => Response::ok().status(404, "NOT FOUND"),
+        }
+    }
+}
+
+// The route is "/kv/:key", so every request that gets here has one
+fn key(request: &Request) -> String {
+    request.param("key").unwrap_or_default().to_string()
+}
@@ -1,18 +1,1068 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    cell::{Cell, RefCell},
+    cmp,
+    collections::{BinaryHeap, VecDeque},
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Condvar, Mutex, OnceLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
-// We'll use this type alias to denote what type of data will be used to send to each Worker
-// In this case, we have a function (closure) that will run once
-type Job = Box<dyn FnOnce() + Send + 'static>;
+mod autoindex;
+mod autoscale;
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+mod compression;
+/// How the server runs, from its options, the environment, and a config file
+pub mod config;
+/// The ways serving a connection, or starting the server, can go wrong
+pub mod error;
+/// The pages that error responses get filled in with
+pub mod error_pages;
+mod handlers;
+mod health;
+mod http_date;
+mod kv;
+mod listener;
+mod logger;
+mod metrics;
+/// What runs around every request, i.e.: authentication
+pub mod middleware;
+/// The parts of a "multipart/form-data" body, i.e.: a form with a file in it
+pub mod multipart;
+mod rate_limit;
+/// A request, as it's read off of a connection
+pub mod request;
+/// What a handler sends back for a request
+pub mod response;
+/// Which handler each request goes to
+pub mod router;
+mod server;
+/// What a client's requests share between them, kept on our side, with only its id in a cookie
+pub mod session;
+mod static_files;
+/// Filling in the placeholders in an HTML page
+pub mod template;
+#[cfg(feature = "tls")]
+mod tls;
+/// Percent-encoding and decoding, for paths, query strings, and forms
+pub mod url;
 
-// Our ThreadPool object contains a list of Workers, as well as a
-// mpsc::Sender, which tells the threads what kind of data that they'll
-// expect to be sent through the Sender's channel, to the receiving end
+pub use server::{Server, ServerHandle};
+
+/// A unit of work for the pool: a closure that a Worker runs once, on its own thread
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// What actually gets put in the queue for the Workers. Most of the time it's a Job to run,
+// but the ThreadPool can also tell whichever Worker receives the message to stop, which is
+// how the pool shrinks without closing the queue on every Worker at once. The Worker's loop
+// matches on every kind of message by name, without a catch-all, so adding a new kind of
+// message won't compile until the Workers know what to do with it
+enum Message {
+    // A job can have a deadline, after which it isn't worth running anymore
+    NewJob(Job, Option<Deadline>),
+    Terminate,
+}
+
+// When a job stops being worth running, and what to run instead, if anything, when a
+// Worker doesn't get to the job until afterwards
+struct Deadline {
+    at: Instant,
+    on_expired: Option<Job>,
+}
+
+impl Message {
+    // Only used to recover the Job from a Message we tried to send but couldn't
+    fn into_job(self) -> Job {
+        match self {
+            Message::NewJob(job, _) => job,
+            Message::Terminate => unreachable!("only jobs are handed back to the caller"),
+        }
+    }
+}
+
+// A message waiting in the queue, along with what decides when it gets picked up
+struct QueuedMessage {
+    priority: u8,
+    // Goes up by one for every message put in the queue, so messages with the same
+    // priority come back out in the same order they went in
+    seq: u64,
+    message: Message,
+}
+
+// BinaryHeap always pops the largest item first, so the higher priority is the larger one,
+// and for equal priorities the *lower* (older) sequence number is the larger one
+impl Ord for QueuedMessage {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for QueuedMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueuedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for QueuedMessage {}
+
+// Why a message couldn't be put in the queue. Either way, the message is handed back
+enum PushError {
+    Full(Message),
+    Closed(Message),
+}
+
+// How many times JobQueue::pop looks through the queues before putting the Worker to sleep
+const SPINS_BEFORE_SLEEP: usize = 64;
+
+// The queue that sits between the ThreadPool and its Workers. This started out as an mpsc
+// channel behind a single Mutex, which meant that every job, on every Worker, had to wait its
+// turn for the same lock. Now each Worker (as of when the pool was built) has a local queue of
+// its own, and the default priority jobs that make up most of the traffic are spread across
+// those round-robin. A Worker takes jobs from its own queue first, and only once that's empty
+// does it go looking through (stealing from) the other Workers' queues, so under load each
+// Worker mostly only touches its own lock.
+//
+// Jobs with any other priority (and Terminate messages) go into a single shared BinaryHeap
+// instead, since their whole point is being ordered against everything else. Those are rare,
+// and a Worker only locks the heap when the counters say there's something in it.
+struct JobQueue {
+    locals: Vec<LocalQueue>,
+    // Which local queue the next default priority job goes into
+    next_local: AtomicUsize,
+    global: Mutex<BinaryHeap<QueuedMessage>>,
+    // Number of messages in the global heap, so Workers can skip locking it when it's empty
+    global_len: AtomicUsize,
+    next_seq: AtomicU64,
+    // Number of messages in the global heap with a priority above the default.
+    // Workers check the heap before their own queue whenever this isn't zero
+    urgent: AtomicUsize,
+    // Number of messages in all of the queues put together. A message is counted before
+    // it's actually pushed, which is how a bounded queue reserves room for it
+    len: AtomicUsize,
+    // Once the queue is closed, nothing else can be pushed, and the Workers
+    // stop as soon as they've emptied it
+    closed: AtomicBool,
+    // Workers with nothing to do wait on the Condvar, and the count lets anyone pushing
+    // a message skip the lock entirely when nobody is waiting. Whoever wakes a Worker takes
+    // it off the count and leaves it a wakeup in the Mutex, so a Worker that's been woken
+    // but hasn't run yet doesn't get woken again by every push after that
+    sleepers: AtomicUsize,
+    sleep_lock: Mutex<usize>,
+    available: Condvar,
+    // The most messages that can be waiting at once, or None for no limit. Anyone submitting
+    // to a bounded queue waits on the Condvar until there's room again
+    capacity: Option<usize>,
+    space_lock: Mutex<()>,
+    space: Condvar,
+}
+
+// One Worker's own queue inside the JobQueue. Each one is aligned to its own cache line, so
+// Workers updating their own queues aren't slowed down by each other (false sharing)
+#[repr(align(64))]
+struct LocalQueue {
+    jobs: Mutex<VecDeque<Message>>,
+    // Same idea as JobQueue::global_len, but for this queue
+    len: AtomicUsize,
+}
+
+impl LocalQueue {
+    fn push(&self, message: Message) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.push_back(message);
+        self.len.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn pop(&self) -> Option<Message> {
+        if self.len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let mut jobs = self.jobs.lock().unwrap();
+        let message = jobs.pop_front()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        Some(message)
+    }
+}
+
+impl JobQueue {
+    fn new(num_locals: usize, capacity: Option<usize>) -> JobQueue {
+        JobQueue {
+            locals: (0..num_locals)
+                .map(|_| LocalQueue {
+                    jobs: Mutex::new(VecDeque::new()),
+                    len: AtomicUsize::new(0),
+                })
+                .collect(),
+            next_local: AtomicUsize::new(0),
+            global: Mutex::new(BinaryHeap::new()),
+            global_len: AtomicUsize::new(0),
+            next_seq: AtomicU64::new(0),
+            urgent: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            sleep_lock: Mutex::new(0),
+            available: Condvar::new(),
+            capacity,
+            space_lock: Mutex::new(()),
+            space: Condvar::new(),
+        }
+    }
+
+    // Adds a message to the queue. If the queue is full, this either waits for there to be
+    // room (when block is true), or hands the message right back
+    fn push(&self, message: Message, priority: u8, block: bool) -> Result<(), PushError> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(PushError::Closed(message));
+        }
+
+        if !self.try_reserve() {
+            if !block {
+                return Err(PushError::Full(message));
+            }
+
+            let mut guard = self.space_lock.lock().unwrap();
+            loop {
+                if self.closed.load(Ordering::SeqCst) {
+                    return Err(PushError::Closed(message));
+                }
+                if self.try_reserve() {
+                    break;
+                }
+                guard = self.space.wait(guard).unwrap();
+            }
+        }
+
+        self.push_reserved(message, priority);
+        Ok(())
+    }
+
+    // Adds a message to the queue whether or not there's room. Only used for
+    // Terminate messages, which shouldn't have to wait behind a full queue
+    fn push_unbounded(&self, message: Message, priority: u8) {
+        self.len.fetch_add(1, Ordering::SeqCst);
+        self.push_reserved(message, priority);
+    }
+
+    // Counts a message that's about to be pushed, if there's room for it. A message fits if
+    // it's under the capacity, not counting the messages that a waiting Worker is about to
+    // take, which is what lets a capacity of zero hand jobs directly to a Worker that's ready
+    fn try_reserve(&self) -> bool {
+        let Some(capacity) = self.capacity else {
+            self.len.fetch_add(1, Ordering::SeqCst);
+            return true;
+        };
+
+        self.len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                let room = capacity + self.sleepers.load(Ordering::SeqCst);
+                (len < room).then_some(len + 1)
+            })
+            .is_ok()
+    }
+
+    fn push_reserved(&self, message: Message, priority: u8) {
+        match message {
+            Message::NewJob(..) if priority == ThreadPool::DEFAULT_PRIORITY => {
+                let index = self.next_local.fetch_add(1, Ordering::Relaxed) % self.locals.len();
+                self.locals[index].push(message);
+            }
+            message => {
+                let mut global = self.global.lock().unwrap();
+                let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+                global.push(QueuedMessage {
+                    priority,
+                    seq,
+                    message,
+                });
+                self.global_len.fetch_add(1, Ordering::SeqCst);
+
+                if priority > ThreadPool::DEFAULT_PRIORITY {
+                    self.urgent.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        // Wake up one of the Workers waiting for a message, if there are any
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let mut wakeups = self.sleep_lock.lock().unwrap();
+            if self.sleepers.load(Ordering::SeqCst) > 0 {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                *wakeups += 1;
+                self.available.notify_one();
+            }
+        }
+    }
+
+    // Waits for a message and takes it, looking in this order: any global message that's more
+    // important than the default, then the Worker's own local queue (home), then the other
+    // Workers' local queues, then whatever is left in the global heap. Returns Disconnected
+    // once the queue has been closed and there's nothing left in it, or Timeout if there's
+    // a timeout and nothing turned up before it ran out
+    fn pop(&self, home: usize, timeout: Option<Duration>) -> Result<Message, RecvTimeoutError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            // Before going to sleep, keep checking for a little while. Under load, the next job
+            // tends to show up within a few microseconds, and catching it here saves both us
+            // and whoever pushes it from the much slower trip through the Condvar
+            for _ in 0..SPINS_BEFORE_SLEEP {
+                if let Some(message) = self.try_pop(home) {
+                    self.len.fetch_sub(1, Ordering::SeqCst);
+
+                    // Taking a message out makes room for someone waiting to put one in
+                    self.notify_space();
+                    return Ok(message);
+                }
+                thread::yield_now();
+            }
+
+            let mut wakeups = self.sleep_lock.lock().unwrap();
+
+            // A Worker that's ready for a job counts as room in the queue too
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            self.notify_space();
+
+            // Look at the count again now that we're registered as a sleeper: if something was
+            // pushed after we looked through the queues, either we see it counted here, or
+            // whoever pushed it sees us sleeping and wakes us up
+            if self.len.load(Ordering::SeqCst) > 0 {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            // Whoever wakes us takes us off the sleepers count, so only a Worker that wakes up
+            // without a wakeup waiting for it (because the queue closed, or it ran out of time)
+            // does that itself
+            loop {
+                wakeups = match deadline {
+                    Some(deadline) => {
+                        let remaining = deadline.saturating_duration_since(Instant::now());
+                        self.available.wait_timeout(wakeups, remaining).unwrap().0
+                    }
+                    None => self.available.wait(wakeups).unwrap(),
+                };
+                if *wakeups > 0 {
+                    *wakeups -= 1;
+                    break;
+                }
+                if self.closed.load(Ordering::SeqCst) {
+                    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                    break;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                    return Err(RecvTimeoutError::Timeout);
+                }
+            }
+        }
+    }
+
+    fn try_pop(&self, home: usize) -> Option<Message> {
+        if self.urgent.load(Ordering::SeqCst) > 0 {
+            if let Some(message) = self.pop_global() {
+                return Some(message);
+            }
+        }
+
+        let num_locals = self.locals.len();
+        for offset in 0..num_locals {
+            if let Some(message) = self.locals[(home + offset) % num_locals].pop() {
+                return Some(message);
+            }
+        }
+
+        self.pop_global()
+    }
+
+    fn pop_global(&self) -> Option<Message> {
+        if self.global_len.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+
+        let mut global = self.global.lock().unwrap();
+        let queued = global.pop()?;
+        self.global_len.fetch_sub(1, Ordering::SeqCst);
+
+        if queued.priority > ThreadPool::DEFAULT_PRIORITY {
+            self.urgent.fetch_sub(1, Ordering::SeqCst);
+        }
+        Some(queued.message)
+    }
+
+    // Only a bounded queue has anyone waiting for room
+    fn notify_space(&self) {
+        if self.capacity.is_some() {
+            let _guard = self.space_lock.lock().unwrap();
+            self.space.notify_one();
+        }
+    }
+
+    // Stops the queue from accepting anything new, and wakes everyone up so
+    // they can notice. Anything already in the queue can still be popped
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+
+        let guard = self.sleep_lock.lock().unwrap();
+        self.available.notify_all();
+        drop(guard);
+
+        let _guard = self.space_lock.lock().unwrap();
+        self.space.notify_all();
+    }
+}
+
+// A job handed to execute_after, waiting for its time to come
+struct DelayedJob {
+    due: Instant,
+    // Same idea as QueuedMessage, so jobs that are due at the same instant
+    // get sent to the pool in the order they were scheduled
+    seq: u64,
+    job: Job,
+}
+
+// BinaryHeap pops the largest item first, but we want the job that's due soonest,
+// so the comparison is backwards: an earlier due time (or sequence number) is larger
+impl Ord for DelayedJob {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for DelayedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for DelayedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for DelayedJob {}
+
+// The thread behind ThreadPool::execute_after. It sleeps until the next delayed job is due,
+// then puts it in the pool's queue like any other job. There's only ever one of these per
+// pool, and it isn't started until the first time a job is scheduled
+struct Timer {
+    state: Arc<(Mutex<TimerState>, Condvar)>,
+    handle: thread::JoinHandle<()>,
+}
+
+struct TimerState {
+    heap: BinaryHeap<DelayedJob>,
+    next_seq: u64,
+    // Set when the pool shuts down, which stops the timer thread
+    // (and drops any jobs that weren't due yet)
+    closed: bool,
+}
+
+impl Timer {
+    fn new(shared: Arc<Shared>) -> io::Result<Timer> {
+        let state = Arc::new((
+            Mutex::new(TimerState {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            Condvar::new(),
+        ));
+        let thread_state = Arc::clone(&state);
+
+        let handle = thread::Builder::new()
+            .name(String::from("thread-pool-timer"))
+            .spawn(move || {
+                let (lock, wake) = &*thread_state;
+                let mut timers = lock.lock().unwrap();
+
+                while !timers.closed {
+                    let now = Instant::now();
+
+                    match timers.heap.peek() {
+                        // Nothing is scheduled, so wait until something is
+                        None => timers = wake.wait(timers).unwrap(),
+                        // The next job is due, so hand it over to the Workers. We let go of our
+                        // lock while we do, since a bounded queue might make us wait for room
+                        Some(delayed) if delayed.due <= now => {
+                            let delayed = timers.heap.pop().unwrap();
+                            drop(timers);
+                            let message = Message::NewJob(delayed.job, None);
+                            let _ = shared.submit(message, ThreadPool::DEFAULT_PRIORITY, true);
+                            timers = lock.lock().unwrap();
+                        }
+                        // Otherwise sleep until it's due, unless something that's due even
+                        // sooner gets scheduled in the meantime and wakes us up
+                        Some(delayed) => {
+                            let timeout = delayed.due - now;
+                            timers = wake.wait_timeout(timers, timeout).unwrap().0;
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Timer { state, handle })
+    }
+
+    fn schedule(&self, due: Instant, job: Job) {
+        let (lock, wake) = &*self.state;
+        let mut timers = lock.lock().unwrap();
+
+        let seq = timers.next_seq;
+        timers.next_seq += 1;
+        timers.heap.push(DelayedJob { due, seq, job });
+
+        // The new job might be due before whatever the timer thread is currently waiting on
+        wake.notify_one();
+    }
+
+    // Stops the timer thread, dropping any jobs that haven't come due yet
+    fn stop(self) {
+        let (lock, wake) = &*self.state;
+        lock.lock().unwrap().closed = true;
+        wake.notify_one();
+
+        self.handle.join().unwrap();
+    }
+}
+
+/// A snapshot of how much work one of the ThreadPool's Workers has done,
+/// as returned by ThreadPool::worker_stats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The Worker's unique id
+    pub id: usize,
+    /// How many jobs the Worker has run, including any that panicked
+    pub jobs_run: u64,
+    /// How long the Worker has spent running jobs
+    pub busy_time: Duration,
+}
+
+/// A read-only view of how busy a ThreadPool is, as returned by ThreadPool::monitor
+///
+/// Unlike the pool itself, this can be cloned and handed to other threads (i.e.: to a
+/// request handler that reports on the pool), while the pool stays with its owner
+#[derive(Clone)]
+pub struct PoolMonitor {
+    shared: Arc<Shared>,
+}
+
+impl PoolMonitor {
+    /// Returns the number of Workers that are currently running a job
+    pub fn active_count(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of jobs waiting in the queue for a Worker to pick them up
+    pub fn queued_count(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of jobs that were dropped without running, because their
+    /// deadline had passed by the time a Worker got to them
+    pub fn expired_count(&self) -> usize {
+        self.shared.expired.load(Ordering::SeqCst)
+    }
+}
+
+/// A way to hand the ThreadPool more jobs from somewhere its owner can't reach, i.e.: from
+/// a job that's running on one of the pool's own Workers, and wants some of its work done
+/// in the background. As returned by ThreadPool::spawner
+///
+/// A job that waits on a job it handed to its own pool can deadlock: if every Worker is
+/// busy with a job that's doing the same, there's nobody left to run the jobs they're all
+/// waiting on. So the Spawner never blocks, and anything that waits on one of its jobs should
+/// have a plan for when the job doesn't start soon enough, like doing the work itself
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    /// Same as ThreadPool::try_execute: if the pool was built with a queue_capacity and
+    /// the queue is full, the job is handed straight back instead of waiting for room
+    ///
+    /// # Errors
+    ///
+    /// Returns TrySendError::Full if the queue is full, or TrySendError::Disconnected
+    /// if the pool is shutting down. Either way, the error holds the rejected job
+    pub fn try_execute<F>(&self, f: F) -> Result<(), mpsc::TrySendError<Job>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let message = Message::NewJob(Box::new(f), None);
+        self.shared
+            .submit(message, ThreadPool::DEFAULT_PRIORITY, false)
+            .map_err(|err| match err {
+                PushError::Full(message) => mpsc::TrySendError::Full(message.into_job()),
+                PushError::Closed(message) => mpsc::TrySendError::Disconnected(message.into_job()),
+            })
+    }
+}
+
+/// Returned from [`ThreadPool::execute`] when the pool can no longer accept work (for example,
+/// because it has already started shutting down). The rejected [`Job`] is kept inside the error
+/// so the caller can retry it somewhere else, or at least log it, instead of losing it.
+pub struct ExecuteError(Job);
+
+impl ExecuteError {
+    /// Gives back the Job that the ThreadPool wasn't able to run
+    pub fn into_job(self) -> Job {
+        self.0
+    }
+}
+
+// Job is a boxed closure, which doesn't implement Debug, so we can't just derive it here
+impl fmt::Debug for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ExecuteError").finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the thread pool is shutting down and can't accept new jobs"
+        )
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// Returned from ThreadPool::execute_with_handle, so the caller can wait on the job
+/// and get back whatever it returned
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished, and gives back its value
+    ///
+    /// If the job panicked, this returns Err(JobError::Panicked). If the job was never
+    /// run at all (because the pool had already shut down, or was shut down with
+    /// `shutdown_now` before getting to it), this returns Err(JobError::PoolShutDown)
+    pub fn join(self) -> Result<T, JobError> {
+        // The job always sends something once it has run, so if the sender was dropped
+        // without sending anything, the job must have been dropped without running
+        self.receiver.recv().unwrap_or(Err(JobError::PoolShutDown))
+    }
+}
+
+impl<T> fmt::Debug for JobHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobHandle").finish_non_exhaustive()
+    }
+}
+
+/// The ways a job behind a JobHandle can fail to give back a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked before it could return
+    Panicked,
+    /// The pool shut down before the job got to run
+    PoolShutDown,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked => write!(f, "the job panicked"),
+            JobError::PoolShutDown => write!(f, "the thread pool shut down before the job ran"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+/// A fixed set of Worker threads that run the [`Job`]s handed to them. The pool owns the
+/// Workers and the queue that jobs get sent to them through, and replaces any Worker whose
+/// thread dies
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    // This is behind a Mutex so a Worker whose thread died can be replaced
+    // from `execute`, which only has a shared reference to the pool
+    workers: Mutex<Vec<Worker>>,
+    shared: Arc<Shared>,
+    // The id to give the next Worker that gets created
+    next_id: AtomicUsize,
+    // How each Worker's thread should be set up when it gets spawned
+    thread_options: ThreadOptions,
+    // Only started the first time execute_after is called
+    timer: OnceLock<Timer>,
+}
+
+// The settings from the ThreadPoolBuilder that get used every time we spawn a Worker's thread,
+// both when the pool is first built and later on when it grows
+#[derive(Clone, Default)]
+struct ThreadOptions {
+    name_prefix: Option<String>,
+    stack_size: Option<usize>,
+    worker_init: Option<WorkerInit>,
+}
+
+// The function from ThreadPoolBuilder::worker_init, with the type of state it creates erased,
+// so that neither ThreadOptions nor the ThreadPool need to be generic over it
+type WorkerInit = Arc<dyn Fn() -> Box<dyn Any> + Send + Sync>;
+
+thread_local! {
+    // The state created by the worker_init function, for the Worker running on this thread
+    static WORKER_STATE: RefCell<Option<Box<dyn Any>>> = const { RefCell::new(None) };
+    // What the job running on this thread said it was doing, with set_job_context
+    static JOB_CONTEXT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Says what the job running on this thread is in the middle of, i.e.: "GET /users/42" for
+/// the request it's handling, so that if it panics, the Worker's message about catching the
+/// panic says what it was doing when it did. It can be called as many times as the job likes,
+/// and the last one is what gets logged. The Worker clears it once the job is done, so it
+/// never carries over to the next job
+///
+/// ```
+/// use web_server_rust::{set_job_context, JobError, ThreadPool};
+///
+/// let pool = ThreadPool::new(1);
+/// // Logs "Worker 0 caught a panic from its job (resizing cat.png); continuing"
+/// let failed = pool.execute_with_handle(|| {
+///     set_job_context("resizing cat.png");
+///     panic!("not an image");
+/// });
+/// assert_eq!(failed.join(), Err(JobError::Panicked));
+/// ```
+pub fn set_job_context(context: impl Into<String>) {
+    JOB_CONTEXT.with(|job_context| *job_context.borrow_mut() = Some(context.into()));
+}
+
+// Clears the job's context, giving back what it was, for the panic message. Nothing's said
+// about a job that didn't set one
+fn take_job_context() -> String {
+    JOB_CONTEXT
+        .with(|job_context| job_context.borrow_mut().take())
+        .map_or_else(String::new, |context| format!(" ({context})"))
+}
+
+/// Gives a job access to the state that the pool's worker_init function created for
+/// the Worker it's running on, returning whatever the closure returns
+///
+/// Returns None if this isn't being called from one of the pool's Workers, if the pool
+/// wasn't built with a worker_init function, if the state isn't actually an S, or if
+/// it's called again from inside the closure (since the state is already borrowed)
+///
+/// ```
+/// use web_server_rust::{with_worker_state, ThreadPool};
+///
+/// let pool = ThreadPool::builder()
+///     .num_threads(2)
+///     .worker_init(|| Vec::<u8>::with_capacity(4096))
+///     .build()
+///     .unwrap();
+///
+/// let len = pool.execute_with_result(|| {
+///     with_worker_state(|buffer: &mut Vec<u8>| {
+///         buffer.clear();
+///         buffer.extend_from_slice(b"reused between jobs");
+///         buffer.len()
+///     })
+/// });
+/// assert_eq!(len.recv().unwrap(), Some(19));
+/// ```
+pub fn with_worker_state<S, R>(f: impl FnOnce(&mut S) -> R) -> Option<R>
+where
+    S: 'static,
+{
+    WORKER_STATE.with(|state| {
+        let mut state = state.try_borrow_mut().ok()?;
+        let state = state.as_mut()?.downcast_mut::<S>()?;
+        Some(f(state))
+    })
+}
+
+/// Lets you configure a ThreadPool before it gets created
+///
+/// ```
+/// use web_server_rust::ThreadPoolBuilder;
+///
+/// let pool = ThreadPoolBuilder::new()
+///     .num_threads(4)
+///     .thread_name_prefix("web-worker")
+///     .stack_size(2 * 1024 * 1024)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct ThreadPoolBuilder {
+    num_threads: Option<usize>,
+    min_threads: Option<usize>,
+    idle_timeout: Option<Duration>,
+    queue_capacity: Option<usize>,
+    thread_options: ThreadOptions,
+}
+
+/// The ways creating a ThreadPool can fail
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// The pool was asked to have zero threads
+    ZeroThreads,
+    /// The operating system wouldn't let us spawn one of the Worker threads
+    Spawn(io::Error),
+}
+
+/// The error returned by ThreadPoolBuilder::build, which is the same
+/// error ThreadPool::build returns
+pub type BuildError = PoolCreationError;
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroThreads => write!(f, "a thread pool needs at least one thread"),
+            PoolCreationError::Spawn(err) => write!(f, "failed to spawn a worker thread: {err}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroThreads => None,
+            PoolCreationError::Spawn(err) => Some(err),
+        }
+    }
+}
+
+impl ThreadPoolBuilder {
+    /// Creates a builder with the default settings. Unless num_threads is called,
+    /// the pool gets one thread for each CPU that's available
+    pub fn new() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::default()
+    }
+
+    /// Sets the number of threads the pool starts out with
+    pub fn num_threads(mut self, num_threads: usize) -> ThreadPoolBuilder {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Sets the fewest Workers the pool shrinks down to on its own when it has an
+    /// idle_timeout. Defaults to one. This doesn't stop `set_size` from going lower
+    pub fn min_threads(mut self, min_threads: usize) -> ThreadPoolBuilder {
+        self.min_threads = Some(min_threads);
+        self
+    }
+
+    /// Lets a Worker that has been waiting for a job for longer than timeout shut itself
+    /// down, as long as the pool has more Workers than min_threads. Use `set_size` to grow
+    /// the pool back when the load picks up again
+    ///
+    /// Without this, Workers wait for jobs for as long as the pool is around
+    pub fn idle_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Names each Worker's thread "{prefix}-{id}", i.e.: "web-worker-0", "web-worker-1", ...
+    ///
+    /// Without a prefix, the threads are left unnamed
+    pub fn thread_name_prefix(mut self, prefix: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_options.name_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets the size of each Worker thread's stack, in bytes
+    ///
+    /// Without this, the threads get the standard library's default stack size
+    pub fn stack_size(mut self, bytes: usize) -> ThreadPoolBuilder {
+        self.thread_options.stack_size = Some(bytes);
+        self
+    }
+
+    /// Runs init once on each Worker's thread, before it picks up its first job, and keeps
+    /// whatever it returns around for that Worker's jobs to use with `with_worker_state`.
+    /// This is the place to set up something expensive, like a database connection or a
+    /// reusable buffer, once per thread instead of once per job
+    ///
+    /// The state itself doesn't need to be Send, since it's created on the Worker's thread
+    /// and never leaves it. The init function does need to be Send + Sync, since every
+    /// Worker (including ones added later on by `set_size`) shares the same one. If init
+    /// panics, the Worker's thread dies, and gets replaced like any other dead Worker would
+    pub fn worker_init<S, F>(mut self, init: F) -> ThreadPoolBuilder
+    where
+        F: Fn() -> S + Send + Sync + 'static,
+        S: 'static,
+    {
+        self.thread_options.worker_init = Some(Arc::new(move || Box::new(init()) as Box<dyn Any>));
+        self
+    }
+
+    /// Limits the number of jobs that can be waiting in the queue at once
+    ///
+    /// Once the queue is full, `execute` blocks until a Worker picks up a job and makes
+    /// room, while `try_execute` returns an error straight away. A capacity of zero means
+    /// every job has to be handed directly to a Worker that's ready for it. Without this,
+    /// the queue can grow without limit
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Creates the ThreadPool, spawning each of its Workers
+    ///
+    /// # Errors
+    ///
+    /// Returns PoolCreationError::ZeroThreads if the pool was given zero threads (or a
+    /// min_threads of zero), or PoolCreationError::Spawn if one of the Worker threads
+    /// couldn't be spawned
+    pub fn build(self) -> Result<ThreadPool, BuildError> {
+        let num_threads = match self.num_threads {
+            Some(num_threads) => num_threads,
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let min_threads = self.min_threads.unwrap_or(1);
+
+        if num_threads == 0 || min_threads == 0 {
+            return Err(PoolCreationError::ZeroThreads);
+        }
+
+        // Create the queue, which allows us to send information (in our case, a Job
+        // object/type) from the ThreadPool to our Workers
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(num_threads, self.queue_capacity),
+            discard_queued: AtomicBool::new(false),
+            active: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            expired: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+            join_lock: Mutex::new(()),
+            all_done: Condvar::new(),
+            exit_lock: Mutex::new(()),
+            worker_exited: Condvar::new(),
+            worker_died: AtomicBool::new(false),
+            size: AtomicUsize::new(num_threads),
+            min_threads,
+            idle_timeout: self.idle_timeout,
+        });
+
+        // Create our list of Workers, giving each one a reference to the queue using Arc::clone()
+        // to create a new reference to the same object for each Worker
+        // Even though all of the Workers have the same queue, the Mutexes inside of it
+        // will allow only one of the Workers to take any given job out of it.
+        let mut workers = Vec::with_capacity(num_threads);
+        for id in 0..num_threads {
+            let worker = Worker::new(id, Arc::clone(&shared), &self.thread_options)
+                .map_err(PoolCreationError::Spawn)?;
+            workers.push(worker);
+        }
+
+        Ok(ThreadPool {
+            workers: Mutex::new(workers),
+            shared,
+            next_id: AtomicUsize::new(num_threads),
+            thread_options: self.thread_options,
+            timer: OnceLock::new(),
+        })
+    }
+}
+
+// Everything the ThreadPool and its Workers both need to get at lives in here, and
+// each Worker gets its own Arc to it
+struct Shared {
+    // The Shared struct is wrapped in an Arc<T>, so every Worker can have a reference to the
+    // same queue, and the queue keeps its contents behind Mutex<T>s, so only one Worker
+    // can take any given job out of it
+    queue: JobQueue,
+    // Set by shutdown_now, so that Workers throw away any jobs that are still
+    // waiting in the queue instead of running them
+    discard_queued: AtomicBool,
+    // Number of Workers currently in the middle of running a job
+    active: AtomicUsize,
+    // Number of jobs sent to the queue that no Worker has picked up yet
+    queued: AtomicUsize,
+    // Number of jobs that were dropped because their deadline passed while they were queued
+    expired: AtomicUsize,
+    // Number of jobs that have been handed to the pool but haven't finished yet (whether
+    // they're still queued, or running right now). ThreadPool::join waits on the Condvar
+    // until this gets back down to zero. The Mutex only gets locked when that happens,
+    // so it stays off of the path every job takes
+    outstanding: AtomicUsize,
+    join_lock: Mutex<()>,
+    all_done: Condvar,
+    // Notified whenever a Worker's thread stops, for ThreadPool::shutdown_timeout to wait on
+    exit_lock: Mutex<()>,
+    worker_exited: Condvar,
+    // Set when a Worker's thread stops unexpectedly, so the next call to `execute`
+    // knows to go looking for it and replace it
+    worker_died: AtomicBool,
+    // The number of Workers the pool is supposed to have. This can be smaller than the
+    // ThreadPool's list of Workers for a little while after shrinking, until the Workers
+    // that were told to stop actually finish and get cleaned up. It lives in here, rather
+    // than in the ThreadPool, because idle Workers take themselves off of it
+    size: AtomicUsize,
+    // Idle Workers only shut themselves down while there are more than this many
+    min_threads: usize,
+    // How long a Worker waits for a job before it tries to shut itself down, if at all
+    idle_timeout: Option<Duration>,
+}
+
+impl Shared {
+    // Does the bookkeeping around putting a job (a Message::NewJob) in the queue for the Workers
+    fn submit(&self, message: Message, priority: u8, block: bool) -> Result<(), PushError> {
+        // The counts go up first, so a Worker never sees them drop below zero,
+        // and come back down if the job didn't make it into the queue after all
+        self.job_submitted();
+        self.queued.fetch_add(1, Ordering::SeqCst);
+
+        self.queue.push(message, priority, block).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            self.job_finished();
+        })
+    }
+
+    // Called by a Worker that has been idle for too long. Takes it off the pool's size and
+    // returns true, unless that would leave the pool with fewer than min_threads Workers
+    fn retire_idle_worker(&self) -> bool {
+        self.size
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |size| {
+                (size > self.min_threads).then(|| size - 1)
+            })
+            .is_ok()
+    }
+
+    // Called when a job is handed to the pool, before it's sent to the Workers
+    fn job_submitted(&self) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Called once a job is finished with, whether it ran, panicked, was discarded,
+    // or was never accepted in the first place
+    fn job_finished(&self) {
+        // Anyone in ThreadPool::join checks the count while holding the lock, so by taking
+        // the lock before notifying, we can't slip in between their check and their wait
+        if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.join_lock.lock().unwrap();
+            self.all_done.notify_all();
+        }
+    }
 }
 
 // Each Worker will have a unique id to identify each one (for debugging or logging)
@@ -20,6 +1070,54 @@ pub struct ThreadPool {
 struct Worker {
     id: usize,
     handle: Option<thread::JoinHandle<()>>,
+    // This is shared with the Worker's thread, since that's where it gets updated
+    state: Arc<WorkerState>,
+}
+
+// What a Worker's thread keeps track of about itself, for the ThreadPool to look at
+struct WorkerState {
+    // How many jobs this Worker has run (including the ones that panicked)
+    jobs_run: AtomicU64,
+    // How long this Worker has spent running jobs, in nanoseconds
+    busy_nanos: AtomicU64,
+    // How many jobs have panicked on this Worker's thread
+    panics: AtomicUsize,
+    // Set if the thread stopped because of a panic that our catch_unwind didn't catch
+    died: AtomicBool,
+    // Set once the thread has stopped running jobs for good, however it stopped
+    exited: AtomicBool,
+}
+
+// Lives on a Worker's thread for as long as it runs. Panics from jobs are caught, so if the
+// thread is unwinding when this gets dropped, something else went wrong, and the thread is
+// about to die. When that happens, we flag the Worker so the ThreadPool can replace it
+struct Sentinel<'a> {
+    shared: &'a Shared,
+    state: &'a WorkerState,
+    // Whether the thread is in the middle of a job. If it dies during one, the job's
+    // counts never get brought back down, so we have to do that here instead
+    // (otherwise ThreadPool::join would wait forever for a job that's never finishing)
+    in_job: Cell<bool>,
+}
+
+impl Drop for Sentinel<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if self.in_job.get() {
+                self.shared.active.fetch_sub(1, Ordering::SeqCst);
+                self.shared.job_finished();
+            }
+
+            self.state.died.store(true, Ordering::SeqCst);
+            self.shared.worker_died.store(true, Ordering::SeqCst);
+        }
+
+        // Same as in Shared::job_finished, the lock makes sure shutdown_timeout can't check
+        // whether we've exited just before we say so, and then miss being told
+        self.state.exited.store(true, Ordering::SeqCst);
+        let _guard = self.shared.exit_lock.lock().unwrap();
+        self.shared.worker_exited.notify_all();
+    }
 }
 
 impl Worker {
@@ -27,103 +1125,784 @@ impl Worker {
     ///
     /// id is a unique identifier (for debugging or logging purposes)
     ///
-    /// receiver expects the receiver from the corresponding mpsc::Sender
-    /// which contains the Job (function/closure) that the Worker should run
-    /// when it receives a request through the receiver.
+    /// shared holds the queue which contains the Jobs (functions/closures) that
+    /// the Worker should run, one at a time, as it takes them out of the queue,
+    /// along with the counters and flags the ThreadPool uses to keep track of its Workers.
+    ///
+    /// options controls the name and stack size of the Worker's thread, and the
+    /// function (if any) that sets up the Worker's state before it runs any jobs
     ///
-    /// If an Err returns from the receiver, that means the Worker/thread
-    /// should be shut down
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let handle = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    /// If the queue has been closed and emptied (or a Message::Terminate comes out of it),
+    /// that means the Worker/thread should be shut down. If the pool has an idle timeout,
+    /// the Worker also shuts down after waiting that long without getting a job, as long
+    /// as the pool has Workers to spare
+    ///
+    /// A job that panics doesn't take the Worker down with it. The panic is caught,
+    /// logged, and counted, and the Worker goes back to waiting for the next job
+    ///
+    /// Returns an Err if the operating system couldn't spawn the thread
+    fn new(id: usize, shared: Arc<Shared>, options: &ThreadOptions) -> io::Result<Worker> {
+        let state = Arc::new(WorkerState {
+            jobs_run: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            panics: AtomicUsize::new(0),
+            died: AtomicBool::new(false),
+            exited: AtomicBool::new(false),
+        });
+        let thread_state = Arc::clone(&state);
+
+        // We use a thread::Builder rather than thread::spawn, so the thread gets the
+        // name and stack size that were asked for when the pool was built
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = &options.name_prefix {
+            builder = builder.name(format!("{prefix}-{id}"));
+        }
+        if let Some(stack_size) = options.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+
+        let worker_init = options.worker_init.clone();
+
+        // Which of the queue's local queues this Worker takes jobs from first. A pool that has
+        // grown past its original size has more Workers than local queues, so some share
+        let home = id % shared.queue.locals.len();
 
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job! Executing...");
-                    job();
+        let handle = builder.spawn(move || {
+            let sentinel = Sentinel {
+                shared: &shared,
+                state: &thread_state,
+                in_job: Cell::new(false),
+            };
+
+            // Set up this Worker's state (if the pool has any) before touching the queue
+            if let Some(init) = worker_init {
+                WORKER_STATE.with(|state| *state.borrow_mut() = Some(init()));
+            }
+
+            loop {
+                let message = shared.queue.pop(home, shared.idle_timeout);
+
+                // Whatever job we received is no longer waiting in the queue
+                if let Ok(Message::NewJob(..)) = message {
+                    shared.queued.fetch_sub(1, Ordering::SeqCst);
                 }
-                Err(_) => {
-                    println!("Worker {id} shutting down");
-                    break;
+
+                match message {
+                    Ok(Message::NewJob(..)) if shared.discard_queued.load(Ordering::SeqCst) => {
+                        println!("Worker {id} discarding a queued job");
+                        shared.job_finished();
+                    }
+                    // Too late to be worth running. The job is dropped, and whatever was meant to
+                    // happen instead runs in its place, the same as a job would, panics and all
+                    Ok(Message::NewJob(_, Some(deadline))) if deadline.at <= Instant::now() => {
+                        shared.expired.fetch_add(1, Ordering::SeqCst);
+                        if let Some(on_expired) = deadline.on_expired {
+                            let result = panic::catch_unwind(AssertUnwindSafe(on_expired));
+                            let context = take_job_context();
+                            if result.is_err() {
+                                thread_state.panics.fetch_add(1, Ordering::SeqCst);
+                                println!(
+                                    "Worker {id} caught a panic from an expired job{context}; \
+                                     continuing"
+                                );
+                            }
+                        }
+                        shared.job_finished();
+                    }
+                    Ok(Message::NewJob(job, _)) => {
+                        shared.active.fetch_add(1, Ordering::SeqCst);
+                        sentinel.in_job.set(true);
+                        let started = Instant::now();
+
+                        // Our Job is a FnOnce, which isn't UnwindSafe, so we have to promise the
+                        // compiler it's ok to catch a panic from it with AssertUnwindSafe. If the job
+                        // does panic, nothing it touched is used again by this Worker, so that's fine.
+                        // Whatever the job said it was doing gets cleared either way
+                        let result = panic::catch_unwind(AssertUnwindSafe(job));
+                        let context = take_job_context();
+                        if result.is_err() {
+                            thread_state.panics.fetch_add(1, Ordering::SeqCst);
+                            println!(
+                                "Worker {id} caught a panic from its job{context}; continuing"
+                            );
+                        }
+
+                        let busy = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+                        thread_state.busy_nanos.fetch_add(busy, Ordering::SeqCst);
+                        thread_state.jobs_run.fetch_add(1, Ordering::SeqCst);
+
+                        sentinel.in_job.set(false);
+                        shared.active.fetch_sub(1, Ordering::SeqCst);
+                        shared.job_finished();
+                    }
+                    Ok(Message::Terminate) => {
+                        println!("Worker {id} told to terminate; shutting down");
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        // If the pool is already down to its minimum, just keep waiting
+                        if shared.retire_idle_worker() {
+                            println!("Worker {id} has been idle for too long; shutting down");
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("Worker {id} shutting down");
+                        break;
+                    }
                 }
             }
-        });
+        })?;
 
-        Worker {
+        Ok(Worker {
             id,
             handle: Some(handle),
-        }
+            state,
+        })
     }
 }
 
 impl ThreadPool {
+    /// The priority jobs get from `execute` and `try_execute`, which is right in the
+    /// middle, so there's room on either side for jobs that matter more or less
+    pub const DEFAULT_PRIORITY: u8 = 128;
+
     /// Create a new ThreadPool
     ///
     /// The numThreads is the number of available threads in the pool
     ///
+    /// This is a thin wrapper around `ThreadPool::build`, for when a failure
+    /// to create the pool should just stop the program
+    ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero or less
+    /// The `new` function will panic if the size is zero or less,
+    /// or if one of the threads couldn't be spawned
     pub fn new(num_threads: usize) -> ThreadPool {
-        assert!(num_threads > 0);
+        match ThreadPool::build(num_threads) {
+            Ok(pool) => pool,
+            Err(err) => panic!("{err}"),
+        }
+    }
 
-        // Create a channel, which provides a Sender/Receiver, and allows us to send information
-        // (in our case, a Job object/type) to our Workers through the receiver
-        let (sender, receiver) = mpsc::channel();
+    /// Create a new ThreadPool, returning an error instead of panicking
+    ///
+    /// The numThreads is the number of available threads in the pool, which is
+    /// handy when that number comes from user config or an environment variable
+    ///
+    /// # Errors
+    ///
+    /// Returns PoolCreationError::ZeroThreads if num_threads is zero, or
+    /// PoolCreationError::Spawn if one of the threads couldn't be spawned
+    pub fn build(num_threads: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPoolBuilder::new().num_threads(num_threads).build()
+    }
 
-        // We need to wrap our receiver in an Arc<Mutex<T>>
-        //   Arc<T>   = Allows us to have multiple of the same reference, even though we can only have one receiver
-        //   Mutex<T> = Only lets one of the receiver references be used at a time, and other references to the same receiver
-        //              will have to wait until the previous one has finished (let go of the lock/mutex)
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Returns a ThreadPoolBuilder, for when you want more control over
+    /// how the pool's threads get created
+    pub fn builder() -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new()
+    }
 
-        // Create our list of Workers, giving each one a reference to the receiver using Arc::clone()
-        // to create a new reference to the same object for each Worker
-        // Even though all of the Workers have the same receiver, the Mutex the receiver is wrapped in
-        // will allow only one of the Workers to access it at a time.
-        let mut workers = Vec::with_capacity(num_threads);
-        for id in 0..num_threads {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Returns the number of Workers the pool is currently set to have
+    ///
+    /// With an idle_timeout, this goes down on its own as idle Workers shut themselves down
+    pub fn size(&self) -> usize {
+        self.shared.size.load(Ordering::SeqCst)
+    }
+
+    /// Grows or shrinks the pool to new_size Workers
+    ///
+    /// Growing spawns the new Workers right away. Shrinking tells the extra Workers
+    /// to stop once they've finished whatever job they're running, and doesn't wait
+    /// for them to do so. Setting the pool to the size it already is does nothing.
+    ///
+    /// # Panics
+    ///
+    /// The `set_size` function will panic if new_size is zero,
+    /// or if one of the new threads couldn't be spawned
+    pub fn set_size(&mut self, new_size: usize) {
+        assert!(new_size > 0);
+
+        // While we're here, clean up any Workers that have already stopped from an earlier shrink
+        // (and replace any that died), so we're starting from an accurate list of Workers
+        self.replace_dead_workers();
+
+        // Idle Workers can shut themselves down while this is going on, so we go one Worker
+        // at a time, looking at the size again each time around
+        let workers = self.workers.get_mut().unwrap();
+        loop {
+            let size = self.shared.size.load(Ordering::SeqCst);
+
+            if size < new_size {
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                let worker = Worker::new(id, Arc::clone(&self.shared), &self.thread_options)
+                    .expect("failed to spawn a worker thread");
+                workers.push(worker);
+                self.shared.size.fetch_add(1, Ordering::SeqCst);
+            } else if size > new_size {
+                // Any Worker can pick up a Terminate message, so we don't know (or care) which
+                // of them are the ones that stop, only that the right number of them do
+                let shrunk = self.shared.size.compare_exchange(
+                    size,
+                    size - 1,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+                if shrunk.is_ok() {
+                    self.shared
+                        .queue
+                        .push_unbounded(Message::Terminate, ThreadPool::DEFAULT_PRIORITY);
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Goes through the Workers, joining and removing the ones whose threads have stopped.
+    // A Worker that stopped because it was told to terminate is just removed, but one whose
+    // thread died unexpectedly gets replaced by a brand new Worker, so the pool stays the
+    // size it's supposed to be
+    fn replace_dead_workers(&self) {
+        let mut workers = self.workers.lock().unwrap();
+
+        for worker in workers.iter_mut() {
+            if !worker.state.died.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            // The thread is on its way out (if it isn't gone already), so this won't wait
+            // long. It returns the panic that killed it, which has already been printed
+            if let Some(handle) = worker.handle.take() {
+                let _ = handle.join();
+            }
+
+            let new_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            println!(
+                "Worker {} died unexpectedly; replacing it with worker {new_id}",
+                worker.id
+            );
+            *worker = Worker::new(new_id, Arc::clone(&self.shared), &self.thread_options)
+                .expect("failed to spawn a worker thread");
+        }
+
+        workers.retain_mut(|worker| match worker.handle.take() {
+            Some(handle) if handle.is_finished() => {
+                handle.join().unwrap();
+                false
+            }
+            handle => {
+                worker.handle = handle;
+                true
+            }
+        });
+    }
+
+    // Called each time a job is submitted. This is just a load of an atomic
+    // unless a Worker has actually died
+    fn supervise(&self) {
+        if self.shared.worker_died.load(Ordering::Relaxed)
+            && self.shared.worker_died.swap(false, Ordering::SeqCst)
+        {
+            self.replace_dead_workers();
+        }
+    }
+
+    /// Returns the number of Workers that are currently running a job
+    pub fn active_count(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of jobs waiting in the queue for a Worker to pick them up
+    pub fn queued_count(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of jobs that were dropped without running, because their
+    /// deadline had passed by the time a Worker got to them
+    pub fn expired_count(&self) -> usize {
+        self.shared.expired.load(Ordering::SeqCst)
+    }
+
+    /// Returns a PoolMonitor, which gives the same counts as active_count and
+    /// queued_count, but can be kept somewhere the pool can't, i.e.: in another thread
+    ///
+    /// The counts stay readable after the pool has shut down, they just stop changing
+    ///
+    /// ```
+    /// use web_server_rust::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let monitor = pool.monitor();
+    ///
+    /// let idle = std::thread::spawn(move || monitor.active_count() + monitor.queued_count());
+    /// assert_eq!(idle.join().unwrap(), 0);
+    /// ```
+    pub fn monitor(&self) -> PoolMonitor {
+        PoolMonitor {
+            shared: Arc::clone(&self.shared),
         }
+    }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+    /// Returns a Spawner, which can hand the pool jobs from anywhere, including from
+    /// inside of one of the pool's own jobs
+    ///
+    /// ```
+    /// use std::sync::mpsc;
+    /// use web_server_rust::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let spawner = pool.spawner();
+    /// let (sender, receiver) = mpsc::channel();
+    ///
+    /// pool.execute(move || {
+    ///     spawner.try_execute(move || sender.send(42).unwrap()).unwrap();
+    /// })
+    /// .unwrap();
+    /// assert_eq!(receiver.recv().unwrap(), 42);
+    /// ```
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: Arc::clone(&self.shared),
         }
     }
 
     /// Takes a function/closure, and gives it to a thread in the ThreadPool to run
     ///
     /// f: A function/closure, which should only run once
-    pub fn execute<F>(&self, f: F)
+    ///
+    /// The job gets ThreadPool::DEFAULT_PRIORITY. If the pool was built with a
+    /// queue_capacity and the queue is full, this blocks until there's room for the job
+    ///
+    /// # Errors
+    ///
+    /// Returns an ExecuteError if the pool is shutting down and can't accept the job.
+    /// The job can be recovered from the error with `ExecuteError::into_job`
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
+        self.execute_priority(f, ThreadPool::DEFAULT_PRIORITY)
+    }
+
+    /// Same as `execute`, but the job is picked up ahead of any waiting jobs with a
+    /// lower priority. Jobs with the same priority are picked up in the order they
+    /// were submitted. The one exception is ThreadPool::DEFAULT_PRIORITY itself: those
+    /// jobs are spread across the Workers' own queues, so each Worker's share of them
+    /// is picked up in order, but one Worker may get ahead of another
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// priority: Higher numbers go first. Plain `execute` uses ThreadPool::DEFAULT_PRIORITY
+    ///
+    /// # Errors
+    ///
+    /// Returns an ExecuteError if the pool is shutting down and can't accept the job.
+    /// The job can be recovered from the error with `ExecuteError::into_job`
+    pub fn execute_priority<F>(&self, f: F, priority: u8) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Before anything else, make sure none of our Workers have died on us
+        self.supervise();
+
         // The function/closure being sent to our execute function needs to be wrapped
-        // in a Box, to match the Job type which the send function will be expecting, due to the
-        // type definition of the "sender" -> mpsc::Sender<Job>
-        let job = Box::new(f);
+        // in a Box, to match the Job type which the queue will be expecting inside of a Message
+        let job: Job = Box::new(f);
 
-        // Send our job using the "sender" on our ThreadPool, which will send the Job to the
-        // corresponding receiver(s). Each of the workers will receive a request, but the Mutex
-        // on the receiver makes sure that only one Worker can accept and process the request.
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        // Put our job in the queue on our ThreadPool, which all of the Workers are waiting on.
+        // The Mutexes inside of the queue make sure that only one Worker can take out and
+        // process the job. Since we're willing to wait for room, the only way this can fail
+        // is the queue being closed, in which case we get back the job we tried to send.
+        self.shared
+            .submit(Message::NewJob(job, None), priority, true)
+            .map_err(|err| match err {
+                PushError::Full(message) | PushError::Closed(message) => {
+                    ExecuteError(message.into_job())
+                }
+            })
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        // Drop the sender before stopping each of the workers (who each have the corresponding receiver)
-        // so that the jobs don't wait forever and never stop, and no more requests can come in
-        drop(self.sender.take());
+    /// Same as `execute`, except that the job is only worth running until deadline. If
+    /// it's still waiting in the queue by then, the Worker that takes it out drops it without
+    /// running it, and counts it in `expired_count`, i.e.: for a request whose client will
+    /// have given up on it by then
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// # Errors
+    ///
+    /// Returns an ExecuteError if the pool is shutting down and can't accept the job.
+    /// The job can be recovered from the error with `ExecuteError::into_job`
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use web_server_rust::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(1);
+    /// pool.execute(|| std::thread::sleep(Duration::from_millis(100))).unwrap();
+    ///
+    /// let deadline = Instant::now() + Duration::from_millis(10);
+    /// pool.execute_with_deadline(|| panic!("too late to run"), deadline).unwrap();
+    ///
+    /// pool.join();
+    /// assert_eq!(pool.expired_count(), 1);
+    /// ```
+    pub fn execute_with_deadline<F>(&self, f: F, deadline: Instant) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit_with_deadline(Box::new(f), deadline, None)
+    }
 
-        // Then, we'll wait for each worker to finish their request, and then exit each of them
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+    /// Same as `execute_with_deadline`, except that on_expired runs in the job's place, on
+    /// the Worker that found it had expired, i.e.: to tell the job's client it's too late
+    ///
+    /// # Errors
+    ///
+    /// Returns an ExecuteError if the pool is shutting down and can't accept the job.
+    /// The job can be recovered from the error with `ExecuteError::into_job`
+    pub fn execute_with_deadline_or<F, E>(
+        &self,
+        f: F,
+        deadline: Instant,
+        on_expired: E,
+    ) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+        E: FnOnce() + Send + 'static,
+    {
+        self.submit_with_deadline(Box::new(f), deadline, Some(Box::new(on_expired)))
+    }
+
+    fn submit_with_deadline(
+        &self,
+        job: Job,
+        deadline: Instant,
+        on_expired: Option<Job>,
+    ) -> Result<(), ExecuteError> {
+        self.supervise();
+
+        let deadline = Deadline {
+            at: deadline,
+            on_expired,
+        };
+        self.shared
+            .submit(
+                Message::NewJob(job, Some(deadline)),
+                ThreadPool::DEFAULT_PRIORITY,
+                true,
+            )
+            .map_err(|err| match err {
+                PushError::Full(message) | PushError::Closed(message) => {
+                    ExecuteError(message.into_job())
+                }
+            })
+    }
+
+    /// Same as `execute`, except that it never blocks. If the pool was built with a
+    /// queue_capacity and the queue is full, the job is handed straight back instead,
+    /// which lets the caller shed load (i.e.: by responding with a 503)
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// # Errors
+    ///
+    /// Returns TrySendError::Full if the queue is full, or TrySendError::Disconnected
+    /// if the pool is shutting down. Either way, the error holds the rejected job
+    pub fn try_execute<F>(&self, f: F) -> Result<(), mpsc::TrySendError<Job>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.supervise();
+
+        let job: Job = Box::new(f);
+
+        self.shared
+            .submit(
+                Message::NewJob(job, None),
+                ThreadPool::DEFAULT_PRIORITY,
+                false,
+            )
+            .map_err(|err| match err {
+                PushError::Full(message) => mpsc::TrySendError::Full(message.into_job()),
+                PushError::Closed(message) => mpsc::TrySendError::Disconnected(message.into_job()),
+            })
+    }
+
+    /// Takes a function/closure, and gives it to a thread in the ThreadPool to run
+    /// once the delay has passed
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// All of the pool's delayed jobs are kept track of by a single timer thread, which
+    /// puts each one in the queue (with ThreadPool::DEFAULT_PRIORITY) once it's due. Jobs
+    /// that are due at the same time are queued in the order they were scheduled.
+    /// A delayed job doesn't count towards `join` or `queued_count` until it's been queued,
+    /// and any that still aren't due when the pool shuts down are dropped without running
+    ///
+    /// # Panics
+    ///
+    /// The `execute_after` function will panic if the timer thread couldn't be spawned
+    pub fn execute_after<F>(&self, delay: Duration, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let due = Instant::now() + delay;
+
+        let timer = self.timer.get_or_init(|| {
+            Timer::new(Arc::clone(&self.shared)).expect("failed to spawn the timer thread")
+        });
+        timer.schedule(due, Box::new(f));
+    }
+
+    /// Takes a function/closure that returns a value, and gives it to a thread in the
+    /// ThreadPool to run, handing back a Receiver that the value will be sent through
+    /// once the job has finished
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// The caller can block on the Receiver with `recv`, or poll it with `try_recv`.
+    /// If the job never produces a value (because it panicked, or the pool wasn't
+    /// able to accept it), the Receiver will return an Err instead
+    pub fn execute_with_result<F, T>(&self, f: F) -> mpsc::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // Each job gets its own channel, which only ever has the one value sent through it
+        let (sender, receiver) = mpsc::channel();
+
+        // If the pool rejects the job, the sender inside of it gets dropped along with it,
+        // which is what lets the Receiver know that no value is coming
+        let _ = self.execute(move || {
+            // The caller may have dropped the Receiver because they didn't care about the
+            // value after all, so there's no need to do anything if sending it fails
+            let _ = sender.send(f());
+        });
+
+        receiver
+    }
+
+    /// Like `execute_with_result`, but hands back a JobHandle, whose `join` blocks until
+    /// the job has finished and says whether it panicked, instead of leaving the caller
+    /// to work out why a Receiver came back empty
+    ///
+    /// f: A function/closure, which should only run once
+    ///
+    /// ```
+    /// use web_server_rust::{JobError, ThreadPool};
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let sum = pool.execute_with_handle(|| (1..=10).sum::<u32>());
+    /// let failed = pool.execute_with_handle(|| -> u32 { panic!("oops") });
+    ///
+    /// assert_eq!(sum.join(), Ok(55));
+    /// assert_eq!(failed.join(), Err(JobError::Panicked));
+    /// ```
+    pub fn execute_with_handle<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
 
+        // If the pool rejects the job, the sender gets dropped along with it,
+        // which `join` reports as the pool having shut down
+        let _ = self.execute(move || match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(value) => {
+                let _ = sender.send(Ok(value));
+            }
+            Err(payload) => {
+                let _ = sender.send(Err(JobError::Panicked));
+                // Keep panicking once the handle knows about it, so the Worker
+                // still logs the panic and counts it just like any other job's
+                panic::resume_unwind(payload);
+            }
+        });
+
+        JobHandle { receiver }
+    }
+
+    /// Same as `execute`, but panics instead of returning an error
+    /// if the pool can't accept the job
+    ///
+    /// # Panics
+    ///
+    /// The `execute_or_panic` function will panic if the pool is shutting down
+    pub fn execute_or_panic<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Err(err) = self.execute(f) {
+            panic!("{err}");
+        }
+    }
+
+    /// Returns how many panicking jobs each Worker has caught so far,
+    /// as a list of (worker id, panic count) pairs
+    pub fn panic_counts(&self) -> Vec<(usize, usize)> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| (worker.id, worker.state.panics.load(Ordering::SeqCst)))
+            .collect()
+    }
+
+    /// Returns how many jobs each Worker has run, and how long it has spent running them
+    ///
+    /// Comparing these across Workers shows how evenly the work is being spread out
+    pub fn worker_stats(&self) -> Vec<WorkerStats> {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|worker| WorkerStats {
+                id: worker.id,
+                jobs_run: worker.state.jobs_run.load(Ordering::SeqCst),
+                busy_time: Duration::from_nanos(worker.state.busy_nanos.load(Ordering::SeqCst)),
+            })
+            .collect()
+    }
+
+    /// Blocks until every job that has been handed to the pool so far has finished
+    /// (nothing is queued, and no Worker is running anything), then returns
+    ///
+    /// Unlike `shutdown`, the pool stays usable afterwards, so this can be used as a
+    /// barrier between batches of jobs. Calling this from inside one of the pool's
+    /// own jobs will never return, since that job is one of the ones being waited on
+    pub fn join(&self) {
+        let mut guard = self.shared.join_lock.lock().unwrap();
+        while self.shared.outstanding.load(Ordering::SeqCst) > 0 {
+            guard = self.shared.all_done.wait(guard).unwrap();
+        }
+    }
+
+    /// Stops accepting new jobs, and blocks until every job that was already
+    /// queued has finished running, before shutting down each of the Workers
+    ///
+    /// This does the same thing as dropping the ThreadPool, but lets you
+    /// choose exactly when it happens
+    pub fn shutdown(mut self) {
+        self.join_workers();
+    }
+
+    /// Stops accepting new jobs, and shuts down each of the Workers as soon as they
+    /// finish the job they're currently running
+    ///
+    /// Any jobs still waiting in the queue are dropped without being run
+    pub fn shutdown_now(mut self) {
+        self.shared.discard_queued.store(true, Ordering::SeqCst);
+        self.join_workers();
+    }
+
+    /// Stops accepting new jobs, and waits up to timeout for every job that was
+    /// already queued to finish, the same as `shutdown`, except that it gives up on
+    /// any Workers that are still running a job once the time is up
+    ///
+    /// A thread can't be stopped from the outside, so a Worker that's given up on is left
+    /// to carry on in the background, until its job finishes (or the program exits)
+    ///
+    /// # Errors
+    ///
+    /// Returns the ids of the Workers that hadn't finished in time
+    ///
+    /// ```
+    /// use std::{thread, time::Duration};
+    /// use web_server_rust::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// pool.execute(|| thread::sleep(Duration::from_secs(60))).unwrap();
+    /// thread::sleep(Duration::from_millis(50));
+    ///
+    /// let stuck = pool.shutdown_timeout(Duration::from_millis(100)).unwrap_err();
+    /// assert_eq!(stuck.len(), 1);
+    /// ```
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Result<(), Vec<usize>> {
+        let deadline = Instant::now() + timeout;
+        self.close();
+
+        let workers = self.workers.get_mut().unwrap();
+        let running = |workers: &Vec<Worker>| {
+            workers.iter().any(|worker| {
+                worker.handle.is_some() && !worker.state.exited.load(Ordering::SeqCst)
+            })
+        };
+
+        let mut guard = self.shared.exit_lock.lock().unwrap();
+        while running(workers) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            guard = self
+                .shared
+                .worker_exited
+                .wait_timeout(guard, deadline - now)
+                .unwrap()
+                .0;
+        }
+        drop(guard);
+
+        // Dropping a JoinHandle without joining it is what leaves its thread running on its own
+        let mut stuck = Vec::new();
+        for worker in workers.iter_mut() {
             if let Some(handle) = worker.handle.take() {
-                handle.join().unwrap();
+                if worker.state.exited.load(Ordering::SeqCst) {
+                    let _ = handle.join();
+                } else {
+                    println!(
+                        "Worker {} didn't finish in time; leaving it behind",
+                        worker.id
+                    );
+                    stuck.push(worker.id);
+                }
             }
         }
+
+        if stuck.is_empty() {
+            Ok(())
+        } else {
+            Err(stuck)
+        }
+    }
+
+    // Stops the timer and closes the queue, so nothing new can be run. Workers carry on
+    // with whatever is already in the queue (unless it's being discarded), and then stop
+    fn close(&mut self) {
+        // Stop the timer first, since it's the only other thing putting jobs in the queue
+        if let Some(timer) = self.timer.take() {
+            timer.stop();
+        }
+
+        // Close the queue before stopping each of the workers, so that they don't wait forever
+        // for another job and never stop, and no more requests can come in
+        self.shared.queue.close();
+    }
+
+    // Shared by shutdown, shutdown_now, and Drop. Once this has run, the queue is closed
+    // and every worker handle has been taken, so calling it a second time does nothing
+    fn join_workers(&mut self) {
+        self.close();
+
+        // Then, we'll wait for each worker to finish their request, and then exit each of them
+        for worker in self.workers.get_mut().unwrap().iter_mut() {
+            if let Some(handle) = worker.handle.take() {
+                println!("Shutting down worker {}", worker.id);
+
+                // An Err here means the thread died from a panic, which was already printed
+                // when it happened, and there's nothing left to clean up for that Worker
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // If shutdown (or shutdown_now) already ran, there's nothing left to do here
+        self.join_workers();
     }
 }
@@ -0,0 +1,384 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use crate::config::Listen;
+
+// How long a connection being closed waits for its client to close its end too, and how much
+// of whatever the client sends in the meantime it reads, before giving up on it. It's only
+// waited on when the client had sent something we hadn't read, and it's spent on one of the
+// pool's Workers, so it's kept short
+const LINGER_TIMEOUT: Duration = Duration::from_millis(500);
+const LINGER_LIMIT: usize = 1024 * 1024;
+
+// Somewhere connections come in from: a TCP port, or a Unix domain socket, which is a file
+// that programs on the same machine can connect through, without any of the overhead of TCP
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        // The socket's file, which gets deleted when we stop listening
+        path: PathBuf,
+    },
+}
+
+impl Listener {
+    // Starts listening on listen, with room for backlog connections waiting to be accepted
+    //
+    // A TCP port gets SO_REUSEADDR set on it on Unix, where TcpListener::bind always sets it
+    // (before binding, which is the only time it does anything), so a server that's restarted
+    // can listen on the same port straight away, instead of getting "address already in use"
+    // until the last one's connections have finished closing. It isn't an option, since
+    // there's no way to turn it off without setting up the socket ourselves, and on Windows,
+    // where it isn't set, it'd let another program take over the port while we're using it
+    pub fn bind(listen: &Listen, backlog: u32) -> io::Result<Listener> {
+        let listener = Listener::bind_to(listen)?;
+        listener.set_backlog(backlog)?;
+        Ok(listener)
+    }
+
+    fn bind_to(listen: &Listen) -> io::Result<Listener> {
+        match listen {
+            Listen::Tcp(address) => TcpListener::bind(address).map(Listener::Tcp),
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                let listener = match UnixListener::bind(path) {
+                    Ok(listener) => listener,
+                    // The file can be left over from a server that didn't get to clean up after
+                    // itself. If nothing answers when we connect to it, it's safe to replace
+                    Err(err) if err.kind() == io::ErrorKind::AddrInUse => {
+                        if UnixStream::connect(path).is_ok() {
+                            return Err(err);
+                        }
+                        fs::remove_file(path)?;
+                        UnixListener::bind(path)?
+                    }
+                    Err(err) => return Err(err),
+                };
+
+                Ok(Listener::Unix {
+                    listener,
+                    path: path.clone(),
+                })
+            }
+            #[cfg(not(unix))]
+            Listen::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets aren't supported on this platform",
+            )),
+        }
+    }
+
+    // The standard library always listens with a backlog of 128, without any way of asking
+    // for something else. Calling listen again on a socket that's already listening just
+    // changes its backlog, so that's how it gets changed. Anywhere else, it stays at 128
+    #[cfg(unix)]
+    fn set_backlog(&self, backlog: u32) -> io::Result<()> {
+        let backlog = i32::try_from(backlog).unwrap_or(i32::MAX);
+
+        // SAFETY: fd is the listener's own socket, which stays open for as long as self does
+        if unsafe { libc::listen(self.fd(), backlog) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn set_backlog(&self, _backlog: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix { listener, .. } => listener.as_raw_fd(),
+        }
+    }
+
+    // Waits until there's a connection to accept on any of listeners, or timeout is up,
+    // whichever comes first. Listeners are accepted from without blocking, so that one of
+    // them can't hold up the rest, and this is what they wait in instead, which wakes up as
+    // soon as a connection comes in
+    #[cfg(unix)]
+    pub fn wait_for_connection(listeners: &[Listener], timeout: Duration) -> io::Result<()> {
+        let mut fds: Vec<libc::pollfd> = listeners
+            .iter()
+            .map(|listener| libc::pollfd {
+                fd: listener.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+
+        // SAFETY: fds has fds.len() pollfds in it, each of them for a listener's own socket,
+        // which stays open for as long as listeners is borrowed
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } == -1 {
+            let err = io::Error::last_os_error();
+            // A signal (i.e.: the SIGHUP for a reload) waking us up early isn't a problem
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // Anywhere else, there's nothing to wait in, so this just waits out the timeout
+    #[cfg(not(unix))]
+    pub fn wait_for_connection(_listeners: &[Listener], timeout: Duration) -> io::Result<()> {
+        std::thread::sleep(timeout);
+        Ok(())
+    }
+
+    // Where we're actually listening, which for a TCP port of 0 is whichever port the OS
+    // picked for us
+    pub fn local_addr(&self) -> io::Result<Listen> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|address| Listen::Tcp(address.to_string())),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(Listen::Unix(path.clone())),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => listener
+                .accept()
+                .map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
+
+// Unlike a TCP port, a Unix socket's file sticks around after we stop listening on it,
+// and would stop the next server from listening there, so it has to be cleaned up
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// A connection from one of the Listeners. Reading from it and writing to it works the same
+// whichever kind it is, so handle_connection doesn't have to know
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Sets TCP_NODELAY (see ServerConfig::tcp_nodelay). A Unix socket never holds on to what's
+    // written to it in the first place
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Connection::Unix(_) => Ok(()),
+        }
+    }
+
+    // The IP address and port of the client on the other end. Connections over a Unix socket
+    // don't have one, and neither does one whose client has already gone away
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Connection::Unix(_) => None,
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    // Closes the connection once the client has had the chance to read everything we sent it.
+    // Closing a socket that still has data waiting to be read on it (i.e.: requests a client
+    // pipelined behind one that asked us to close the connection) makes the OS reset it, and
+    // the client can lose responses that hadn't reached it yet along with it. So we stop
+    // writing first, which lets the client know we're done, and then, if it had sent anything
+    // we hadn't read, throw away whatever else it sends until it closes its end too
+    //
+    // Usually it hasn't sent anything else, and the connection gets closed straight away,
+    // without waiting on the client at all
+    pub fn linger_close(mut self) {
+        if self.shutdown(Shutdown::Write).is_err() {
+            return;
+        }
+
+        let mut buffer = [0; 8192];
+        let mut discarded = match self.read_unread(&mut buffer) {
+            Some(read) if read > 0 => read,
+            _ => return,
+        };
+
+        let deadline = Instant::now() + LINGER_TIMEOUT;
+        while discarded < LINGER_LIMIT {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || self.set_read_timeout(Some(left)).is_err() {
+                return;
+            }
+            match self.read(&mut buffer) {
+                Ok(0) | Err(_) => return,
+                Ok(read) => discarded += read,
+            }
+        }
+    }
+
+    // Reads whatever the client has already sent, without waiting for anything more. None
+    // means there wasn't anything, and Some(0) that the client has closed its end
+    fn read_unread(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        self.set_nonblocking(true).ok()?;
+        let read = self.read(buffer).ok();
+        self.set_nonblocking(false).ok()?;
+        read
+    }
+}
+
+// Anything that can give up on a read after a while, i.e.: a Connection, or a TLS stream on
+// top of one. handle_connection waits on a client for longer (or shorter) in between its
+// requests than in the middle of one, so it has to be able to change it as it goes
+pub trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl<S: ReadTimeout + ?Sized> ReadTimeout for &mut S {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// A client that's nothing but a pair of buffers, for handing to handle_connection in a test:
+// everything it sends is in input from the start, and everything it gets back ends up in
+// output. Its reads never have to wait on anything, so once it's out of input, it's either
+// hung up, or (made with idle) it's still connected, but every read times out
+#[cfg(test)]
+pub struct MemoryStream {
+    input: io::Cursor<Vec<u8>>,
+    pub output: Vec<u8>,
+    idle: bool,
+}
+
+#[cfg(test)]
+impl MemoryStream {
+    pub fn new(input: &[u8]) -> MemoryStream {
+        MemoryStream {
+            input: io::Cursor::new(input.to_vec()),
+            output: Vec::new(),
+            idle: false,
+        }
+    }
+
+    // A client that sends input, and then stays connected without sending anything else,
+    // the way a socket's read timing out looks (WouldBlock, on Unix)
+    pub fn idle(input: &[u8]) -> MemoryStream {
+        MemoryStream {
+            idle: true,
+            ..MemoryStream::new(input)
+        }
+    }
+}
+
+#[cfg(test)]
+impl ReadTimeout for MemoryStream {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.read(buf)? {
+            0 if self.idle && !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            read => Ok(read),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    http_date::format_log_date,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, Transferred},
+};
+
+// Writes a line for every request that gets served, laid out by a LogFormat. By default,
+// that's the "combined" format most log tools know how to read, with the request's id and
+// how long it took (in microseconds) on the end, i.e.:
+//    127.0.0.1:51234 - - [14/Oct/2026:05:06:19 +0000] "GET /hello.html HTTP/1.1" 200 1261
+//    "-" "curl/8.5.0" 42 1270
+//
+// The lines go to a LogOutput, which the logger shares with the ones built before it and
+// after it, so reloading the config (which builds a new one, in case the format changed)
+// carries on writing to the same place
+pub struct RequestLogger {
+    output: Arc<LogOutput>,
+    format: LogFormat,
+}
+
+impl RequestLogger {
+    pub fn new(output: Arc<LogOutput>, format: LogFormat) -> RequestLogger {
+        RequestLogger { output, format }
+    }
+}
+
+// Where the request log goes, which can be anything that implements Write (stdout, a file,
+// or a Vec<u8> to look at later). It's behind a Mutex since every thread in the pool logs to
+// the same place. Each line is written in one go while holding the lock, so lines from
+// different threads don't get mixed up
+//
+// A file can be reopened while the server's running, since that's what tools like logrotate
+// expect: they move the file out of the way (i.e.: to access.log.1), and then let us know, so
+// we start a new one where it used to be, instead of carrying on in the one they moved. The
+// new file is swapped in while holding the lock too, so every line ends up whole in one file
+// or the other
+pub struct LogOutput {
+    // The file we're logging to, or None for anything that can't be reopened (i.e.: stdout)
+    path: Option<PathBuf>,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl LogOutput {
+    pub fn new(output: impl Write + Send + 'static) -> LogOutput {
+        LogOutput {
+            path: None,
+            output: Mutex::new(Box::new(output)),
+        }
+    }
+
+    // Logs to the end of the file at path, which gets created if it isn't there yet
+    pub fn file(path: &Path) -> io::Result<LogOutput> {
+        Ok(LogOutput {
+            path: Some(path.to_path_buf()),
+            output: Mutex::new(Box::new(open_log(path)?)),
+        })
+    }
+
+    pub fn write_line(&self, line: &str) {
+        let mut output = self.output.lock().unwrap();
+
+        // A log line that can't be written isn't worth failing the request over
+        let _ = writeln!(output, "{line}");
+    }
+
+    // Writes everything from here on to output instead. Whatever the old output was still
+    // holding on to gets written out to it first
+    pub fn swap(&self, output: impl Write + Send + 'static) {
+        let mut current = self.output.lock().unwrap();
+        let _ = current.flush();
+        *current = Box::new(output);
+    }
+
+    // Opens the file we're logging to again, by its path, so the log carries on in whatever
+    // is there now. If it can't be opened, we carry on logging to the old one rather than
+    // nowhere. There's nothing to do when we aren't logging to a file
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            self.swap(open_log(path)?);
+        }
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// As a middleware, the logger times everything after it in the chain, so the earlier it's
+// added to the Router, the more of the work its timings include
+//
+// The line isn't written until the response has been sent, since that's when we know how many
+// bytes it took (for %I and %O). Everything else in it is filled in straight away, while the
+// request and response are still around to fill it in from, and it's all put together before
+// taking the lock, so other threads aren't kept waiting on it for any longer than it takes to
+// write it
+impl Middleware for RequestLogger {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let started = Instant::now();
+        let response = next.run(request);
+        let line = self.format.render(request, &response, started.elapsed());
+
+        let output = Arc::clone(&self.output);
+        response.on_sent(move |transferred| output.write_line(&line.finish(transferred)))
+    }
+}
+
+// How each line of the log is laid out, i.e.: "%h %r %s %b %D", with each "%" directive
+// standing in for something about the request, the same as in Apache's (and nginx's) logs:
+//    %h           who the request is from (see client)
+//    %l, %u       always "-", since we don't know the client's identity or its user
+//    %t           when the request finished, i.e.: "[14/Oct/2026:05:06:19 +0000]"
+//    %r           the request line, i.e.: "GET /search?q=rust HTTP/1.1"
+//    %m, %U, %q   the method, path, and query string (with its "?", if there is one)
+//    %H           the version of HTTP, i.e.: "HTTP/1.1"
+//    %s           the response's status
+//    %b           how many bytes the body is, or "-" for none (or one we don't know the size of)
+//    %D, %T       how long it took, in microseconds or in whole seconds
+//    %I, %O       how many bytes were received for the request, and sent for its response,
+//                 headers included
+//    %L           the request's id
+//    %{Name}i     the value of the request's Name header, i.e.: %{User-Agent}i
+//    %{Name}o     the value of the response's Name header
+//    %%           a "%"
+// Anything else is copied into the line just as it is
+//
+// The format is checked for directives we don't know when it's parsed, so a typo in it
+// stops the server from starting, instead of ending up in every line of the log
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Text(String),
+    Client,
+    Dash,
+    Time,
+    RequestLine,
+    Method,
+    Path,
+    Query,
+    Version,
+    Status,
+    Bytes,
+    Micros,
+    Seconds,
+    Id,
+    Received,
+    Sent,
+    RequestHeader(String),
+    ResponseHeader(String),
+}
+
+impl LogFormat {
+    // The combined format, with the request's id and how long it took on the end
+    pub const COMBINED: &'static str =
+        "%h %l %u %t \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %L %D";
+
+    // Splits a format up into its directives, and the text in between them. A directive we
+    // don't know is given back as the error, i.e.: "%x", or "%{Referer}" without an "i" or "o"
+    pub fn parse(format: &str) -> Result<LogFormat, String> {
+        let mut tokens = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+
+            let token = match chars.next() {
+                Some('%') => {
+                    text.push('%');
+                    continue;
+                }
+                Some('h') => Token::Client,
+                Some('l' | 'u') => Token::Dash,
+                Some('t') => Token::Time,
+                Some('r') => Token::RequestLine,
+                Some('m') => Token::Method,
+                Some('U') => Token::Path,
+                Some('q') => Token::Query,
+                Some('H') => Token::Version,
+                Some('s') => Token::Status,
+                Some('b') => Token::Bytes,
+                Some('D') => Token::Micros,
+                Some('T') => Token::Seconds,
+                Some('L') => Token::Id,
+                Some('I') => Token::Received,
+                Some('O') => Token::Sent,
+                Some('{') => {
+                    let rest = chars.as_str();
+                    let (name, after) = rest.split_once('}').ok_or_else(|| format!("%{{{rest}"))?;
+                    let kind = after.get(..1).unwrap_or_default();
+                    let token = match kind {
+                        "i" if !name.is_empty() => Token::RequestHeader(name.to_string()),
+                        "o" if !name.is_empty() => Token::ResponseHeader(name.to_string()),
+                        _ => return Err(format!("%{{{name}}}{kind}")),
+                    };
+                    chars = after[1..].chars();
+                    token
+                }
+                Some(other) => return Err(format!("%{other}")),
+                None => return Err("%".to_string()),
+            };
+
+            if !text.is_empty() {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            tokens.push(token);
+        }
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+
+        Ok(LogFormat { tokens })
+    }
+
+    // The log line for a request, and the response it got, up to how many bytes they took
+    fn render(&self, request: &Request, response: &Response, elapsed: Duration) -> PendingLine {
+        let mut pieces = Vec::new();
+        let mut line = String::new();
+        let query = if request.query_string.is_empty() {
+            String::new()
+        } else {
+            format!("?{}", request.query_string)
+        };
+
+        for token in &self.tokens {
+            // Anything the client sent is escaped, so it can't pass itself off as one of the
+            // other fields, i.e.: a User-Agent with a '"' in it
+            let _ = match token {
+                Token::Text(text) => write!(line, "{text}"),
+                Token::Client => write!(line, "{}", client(request)),
+                Token::Dash => write!(line, "-"),
+                Token::Time => write!(line, "[{}]", format_log_date(SystemTime::now())),
+                Token::RequestLine => write!(
+                    line,
+                    "{} {}{} {}",
+                    request.method,
+                    escape(&request.path),
+                    escape(&query),
+                    request.version
+                ),
+                Token::Method => write!(line, "{}", request.method),
+                Token::Path => write!(line, "{}", escape(&request.path)),
+                Token::Query => write!(line, "{}", escape(&query)),
+                Token::Version => write!(line, "{}", request.version),
+                Token::Status => write!(line, "{}", response.status_code()),
+                Token::Bytes => match response.body_len() {
+                    Some(len) if len > 0 => write!(line, "{len}"),
+                    _ => write!(line, "-"),
+                },
+                Token::Micros => write!(line, "{}", elapsed.as_micros()),
+                Token::Seconds => write!(line, "{}", elapsed.as_secs()),
+                Token::Id => write!(line, "{}", request.id),
+                Token::Received | Token::Sent => {
+                    pieces.push(Piece::Text(std::mem::take(&mut line)));
+                    pieces.push(if matches!(token, Token::Received) {
+                        Piece::Received
+                    } else {
+                        Piece::Sent
+                    });
+                    Ok(())
+                }
+                Token::RequestHeader(name) => {
+                    write!(line, "{}", request.header(name).map_or("-".into(), escape))
+                }
+                Token::ResponseHeader(name) => {
+                    write!(
+                        line,
+                        "{}",
+                        response.header_value(name).map_or("-".into(), escape)
+                    )
+                }
+            };
+        }
+        pieces.push(Piece::Text(line));
+        PendingLine { pieces }
+    }
+}
+
+// A log line that's been filled in with everything but how many bytes its request and response
+// took, which have to wait until the response has been sent
+struct PendingLine {
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Text(String),
+    Received,
+    Sent,
+}
+
+impl PendingLine {
+    fn finish(self, transferred: Transferred) -> String {
+        let mut line = String::new();
+        for piece in self.pieces {
+            match piece {
+                Piece::Text(text) => line.push_str(&text),
+                Piece::Received => line.push_str(&transferred.received.to_string()),
+                Piece::Sent => line.push_str(&transferred.sent.to_string()),
+            }
+        }
+        line
+    }
+}
+
+// Who a request is from, for its log line. That's the address and port it came from, unless
+// a proxy passed it on for a client somewhere else, whose port we don't know, or "-" if it
+// came over a Unix socket
+fn client(request: &Request) -> String {
+    match (request.peer, request.client_ip) {
+        (Some(peer), Some(ip)) if peer.ip() == ip => peer.to_string(),
+        (_, Some(ip)) => ip.to_string(),
+        (_, None) => "-".to_string(),
+    }
+}
+
+// Escapes quotes and backslashes with a backslash, and writes anything that isn't printable
+// ASCII as "\xHH", i.e.: 'say "hi"' is 'say \"hi\"'
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(char::from(byte));
+            }
+            b' '..=b'~' => escaped.push(char::from(byte)),
+            byte => {
+                let _ = write!(escaped, "\\x{byte:02x}");
+            }
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn keeps_every_line_whole_across_a_reopen() {
+        let dir = std::env::temp_dir().join(format!("web-server-rust-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let output = Arc::new(LogOutput::file(&path).unwrap());
+        let rotated = Arc::new(AtomicBool::new(false));
+
+        // Four threads log as fast as they can, until the log's been rotated out from under
+        // them a few times, and then say how many lines they logged
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let (output, rotated) = (Arc::clone(&output), Arc::clone(&rotated));
+                thread::spawn(move || {
+                    let mut lines = 0;
+                    while !rotated.load(Ordering::Relaxed) {
+                        output.write_line(&format!(
+                            "writer {writer} line {lines} {}",
+                            "x".repeat(64)
+                        ));
+                        lines += 1;
+                    }
+                    lines
+                })
+            })
+            .collect();
+        // Each file gets some lines before it's moved, so every one of them gets written to
+        for rotation in 1..=5 {
+            while fs::metadata(&path).unwrap().len() == 0 {
+                thread::yield_now();
+            }
+            fs::rename(&path, dir.join(format!("access.log.{rotation}"))).unwrap();
+            output.reopen().unwrap();
+        }
+        rotated.store(true, Ordering::Relaxed);
+        let written: Vec<usize> = writers
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .collect();
+        output.swap(io::sink());
+
+        let mut lines = Vec::new();
+        for file in fs::read_dir(&dir).unwrap() {
+            let contents = fs::read_to_string(file.unwrap().path()).unwrap();
+            assert!(contents.is_empty() || contents.ends_with('\n'));
+            lines.extend(contents.lines().map(str::to_string));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Every line each writer logged turns up once, whole, in one file or another
+        let mut expected: Vec<String> = written
+            .iter()
+            .enumerate()
+            .flat_map(|(writer, &count)| {
+                (0..count)
+                    .map(move |line| format!("writer {writer} line {line} {}", "x".repeat(64)))
+            })
+            .collect();
+        expected.sort();
+        lines.sort();
+        assert_eq!(lines.len(), expected.len());
+        assert!(
+            lines == expected,
+            "some lines were lost, split up, or mixed together"
+        );
+    }
+}
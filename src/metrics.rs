@@ -0,0 +1,222 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::PoolMonitor;
+
+use crate::{
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, Transferred},
+};
+
+// The upper bounds of the request duration histogram's buckets, in seconds. Anything slower
+// than the last one only shows up in the "+Inf" bucket
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Counts how many requests we've served, how they went, and how long they took, for
+// something like Prometheus to come and collect, i.e.:
+//    http_requests_total 1027
+//    http_responses_total{class="2xx"} 1002
+//    threadpool_active_workers 3
+//
+// Every thread in the pool updates the same counters, so they're all atomics, and nothing
+// has to wait on a lock just to add one to them
+pub struct Metrics {
+    requests: AtomicU64,
+    // One for each class of status, from 1xx (at index 0) to 5xx (at index 4)
+    responses: [AtomicU64; 5],
+    // How many requests took no longer than each bucket's bound, but longer than the bound
+    // before it, with one more at the end for anything slower than all of them. Prometheus
+    // wants each bucket to include the ones before it, so they get added up when rendered
+    durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_nanos: AtomicU64,
+    // The bytes received for requests, and sent for their responses, which are only known once
+    // each response has been sent, after the middleware's done with it
+    bytes: Arc<ByteCounts>,
+    pool: PoolMonitor,
+}
+
+#[derive(Default)]
+struct ByteCounts {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(pool: PoolMonitor) -> Metrics {
+        Metrics {
+            requests: AtomicU64::new(0),
+            responses: Default::default(),
+            durations: Default::default(),
+            duration_nanos: AtomicU64::new(0),
+            bytes: Arc::default(),
+            pool,
+        }
+    }
+
+    pub fn record(&self, status: u16, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        // Anything outside of 100-599 isn't a real status, but still has to be counted
+        // somewhere, so it goes in with the closest class
+        let class = usize::from(status / 100).clamp(1, 5) - 1;
+        self.responses[class].fetch_add(1, Ordering::Relaxed);
+
+        let seconds = elapsed.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.duration_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    // Writes out every counter in Prometheus' text format. The counters are read one at a
+    // time while other threads keep updating them, so they can be off from each other by
+    // the few requests that finished in between, which is fine for what they're used for
+    pub fn render(&self, _request: &Request) -> Response {
+        let mut text = String::new();
+
+        metric(
+            &mut text,
+            "http_requests_total",
+            "counter",
+            "Requests served.",
+        );
+        let _ = writeln!(
+            text,
+            "http_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        );
+
+        metric(
+            &mut text,
+            "http_responses_total",
+            "counter",
+            "Responses sent, by the class of their status.",
+        );
+        for (class, count) in self.responses.iter().enumerate() {
+            let _ = writeln!(
+                text,
+                "http_responses_total{{class=\"{}xx\"}} {}",
+                class + 1,
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        metric(
+            &mut text,
+            "http_request_bytes_total",
+            "counter",
+            "Bytes received for requests, headers included.",
+        );
+        let _ = writeln!(
+            text,
+            "http_request_bytes_total {}",
+            self.bytes.received.load(Ordering::Relaxed)
+        );
+        metric(
+            &mut text,
+            "http_response_bytes_total",
+            "counter",
+            "Bytes sent for responses, headers included.",
+        );
+        let _ = writeln!(
+            text,
+            "http_response_bytes_total {}",
+            self.bytes.sent.load(Ordering::Relaxed)
+        );
+
+        metric(
+            &mut text,
+            "threadpool_active_workers",
+            "gauge",
+            "Workers in the thread pool that are running a job.",
+        );
+        let _ = writeln!(
+            text,
+            "threadpool_active_workers {}",
+            self.pool.active_count()
+        );
+        metric(
+            &mut text,
+            "threadpool_queued_jobs",
+            "gauge",
+            "Jobs waiting for a worker in the thread pool.",
+        );
+        let _ = writeln!(text, "threadpool_queued_jobs {}", self.pool.queued_count());
+        metric(
+            &mut text,
+            "threadpool_expired_jobs_total",
+            "counter",
+            "Jobs dropped because they waited in the queue for too long.",
+        );
+        let _ = writeln!(
+            text,
+            "threadpool_expired_jobs_total {}",
+            self.pool.expired_count()
+        );
+
+        metric(
+            &mut text,
+            "http_request_duration_seconds",
+            "histogram",
+            "How long requests took to handle.",
+        );
+        let mut total = 0;
+        for (bucket, count) in self.durations.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let le = match DURATION_BUCKETS.get(bucket) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                text,
+                "http_request_duration_seconds_bucket{{le=\"{le}\"}} {total}"
+            );
+        }
+        let _ = writeln!(
+            text,
+            "http_request_duration_seconds_sum {}",
+            Duration::from_nanos(self.duration_nanos.load(Ordering::Relaxed)).as_secs_f64()
+        );
+        let _ = writeln!(text, "http_request_duration_seconds_count {total}");
+
+        Response::ok()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(text)
+    }
+}
+
+// As a middleware, every request that makes it past here gets counted and timed, along with
+// everything after it in the chain, the same way the RequestLogger does
+impl Middleware for Metrics {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let started = Instant::now();
+        let response = next.run(request);
+        self.record(response.status_code(), started.elapsed());
+
+        let bytes = Arc::clone(&self.bytes);
+        response.on_sent(move |transferred: Transferred| {
+            bytes
+                .received
+                .fetch_add(transferred.received, Ordering::Relaxed);
+            bytes.sent.fetch_add(transferred.sent, Ordering::Relaxed);
+        })
+    }
+}
+
+// The two comment lines that go above each metric, saying what it is and what type it is
+fn metric(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} {kind}");
+}
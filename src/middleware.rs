@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use crate::{
+    request::{HttpMethod, Request},
+    response::Response,
+};
+
+/// Something that runs around every request the Router handles, for the things that aren't
+/// the job of any one handler (logging, auth, and so on). It's given the request along with
+/// the rest of the chain, and can either call next.run to carry on down it (and then change
+/// the response that comes back), or respond on its own without the handler ever running
+pub trait Middleware: Send + Sync {
+    /// Handles request, usually by passing it on with next.run(request), and giving back the
+    /// response that comes back (or one based on it)
+    fn handle(&self, request: &Request, next: &Next) -> Response;
+}
+
+// A middleware that something else needs to get at too (i.e.: the Metrics, which a handler
+// reads back out) can be wrapped in an Arc, and the Router given a clone of it
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        (**self).handle(request, next)
+    }
+}
+
+/// Whatever comes after a middleware: the middlewares that were registered after it, and then
+/// the handler the Router picked for the request
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    handler: &'a (dyn Fn(&Request) -> Response + Send + Sync),
+}
+
+impl<'a> Next<'a> {
+    /// The chain of middlewares, in the order they run in, with the handler at the end of it
+    pub fn new(
+        middlewares: &'a [Box<dyn Middleware>],
+        handler: &'a (dyn Fn(&Request) -> Response + Send + Sync),
+    ) -> Next<'a> {
+        Next {
+            middlewares,
+            handler,
+        }
+    }
+
+    /// Runs the next middleware in the chain, or the handler once there are none left
+    pub fn run(&self, request: &Request) -> Response {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, &Next::new(rest, self.handler)),
+            None => (self.handler)(request),
+        }
+    }
+}
+
+/// Turns away any request that doesn't come with `Authorization: Bearer <token>`,
+/// with a 401, before it gets anywhere near the handler
+pub struct TokenAuth {
+    token: String,
+}
+
+impl TokenAuth {
+    /// Only lets in requests that come with token
+    pub fn new(token: impl Into<String>) -> TokenAuth {
+        TokenAuth {
+            token: token.into(),
+        }
+    }
+}
+
+impl Middleware for TokenAuth {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        // The scheme is case-insensitive, so "bearer" is just as good as "Bearer"
+        let token = request
+            .header("Authorization")
+            .and_then(|authorization| authorization.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim());
+
+        if token.is_some_and(|token| constant_time_eq(token.as_bytes(), self.token.as_bytes())) {
+            next.run(request)
+        } else {
+            Response::ok()
+                .status(401, "UNAUTHORIZED")
+                .header("WWW-Authenticate", "Bearer")
+        }
+    }
+}
+
+/// Lets scripts on other sites (the origins) call us from the browser, using CORS. Before a
+/// script can send anything out of the ordinary, the browser checks with us first by sending
+/// a "preflight" OPTIONS request, which gets answered here with what's allowed, without it
+/// ever reaching a handler. Every other request from one of the origins goes through as
+/// normal, and its response gets an Access-Control-Allow-Origin header, which is what tells
+/// the browser to let the script see it
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// i.e.: `https://example.com`, or "*" for any origin at all
+    pub origins: Vec<String>,
+    /// The methods scripts can use, i.e.: "GET"
+    pub methods: Vec<String>,
+    /// The headers scripts can send, on top of the ones browsers always allow
+    pub headers: Vec<String>,
+}
+
+impl Default for Cors {
+    // No origins, so nothing is allowed until some are added
+    fn default() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: vec!["Content-Type".to_string()],
+        }
+    }
+}
+
+impl Cors {
+    // What to put in Access-Control-Allow-Origin for a request from origin, if anything
+    fn allowed_origin<'a>(&'a self, origin: &'a str) -> Option<&'a str> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            self.origins
+                .iter()
+                .any(|allowed| allowed == origin)
+                .then_some(origin)
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        // Requests without an Origin didn't come from a script on another site
+        let Some(origin) = request.header("Origin") else {
+            return next.run(request);
+        };
+        let allowed_origin = self.allowed_origin(origin);
+
+        let preflight = request.method == HttpMethod::Options
+            && request.header("Access-Control-Request-Method").is_some();
+        let mut response = if preflight {
+            // An origin that isn't allowed gets nothing but the 204, which the
+            // browser takes as a no
+            let response = Response::ok().status(204, "NO CONTENT");
+            if allowed_origin.is_none() {
+                return response;
+            }
+            response
+                .header("Access-Control-Allow-Methods", &self.methods.join(", "))
+                .header("Access-Control-Allow-Headers", &self.headers.join(", "))
+        } else {
+            next.run(request)
+        };
+
+        if let Some(allowed_origin) = allowed_origin {
+            response = response.header("Access-Control-Allow-Origin", allowed_origin);
+        }
+        // Unless every origin is allowed, the response depends on which origin asked for
+        // it, so caches shouldn't hand one origin's response to another
+        if allowed_origin != Some("*") {
+            response = response.header("Vary", "Origin");
+        }
+        response
+    }
+}
+
+// Whether a and b are the same, taking just as long to work it out however much of them
+// matches, so that someone guessing a token or a password can't tell from how long we took
+// to turn them away how many of its bytes they got right. Only the length can be told apart that way,
+// which doesn't give away anything worth having
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let differences = a
+        .iter()
+        .zip(b)
+        .fold(0, |differences, (x, y)| differences | (x ^ y));
+    (differences == 0) & (a.len() == b.len())
+}
+
+// Decides whether a username and password are allowed in
+type CredentialCheck = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Turns away any request that doesn't come with a username and password that check is happy
+/// with, sent the way HTTP Basic Authentication sends them:
+/// ```text
+/// "Authorization: Basic " followed by "username:password" in base64
+/// ```
+/// Browsers ask the user for these when they get a 401 with "WWW-Authenticate: Basic"
+pub struct BasicAuth {
+    // Shown to the user by the browser, so they know which password it wants
+    realm: String,
+    check: CredentialCheck,
+}
+
+impl BasicAuth {
+    /// Only lets in the one username and password
+    pub fn new(realm: &str, username: &str, password: &str) -> BasicAuth {
+        let (username, password) = (username.to_string(), password.to_string());
+        // Both get checked, even when the username is wrong, so it can't be told from how
+        // long we took whether it was the username or the password that was
+        BasicAuth::with_check(realm, move |user, pass| {
+            constant_time_eq(user.as_bytes(), username.as_bytes())
+                & constant_time_eq(pass.as_bytes(), password.as_bytes())
+        })
+    }
+
+    /// Lets in anyone that check returns true for, given the username and password they sent
+    pub fn with_check<F>(realm: &str, check: F) -> BasicAuth
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        BasicAuth {
+            realm: realm.to_string(),
+            check: Box::new(check),
+        }
+    }
+
+    // The credentials from an Authorization header, if it has any we can make sense of
+    fn credentials(authorization: &str) -> Option<(String, String)> {
+        let (scheme, encoded) = authorization.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = String::from_utf8(decode_base64(encoded.trim())?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some((username.to_string(), password.to_string()))
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let allowed = request
+            .header("Authorization")
+            .and_then(BasicAuth::credentials)
+            .is_some_and(|(username, password)| (self.check)(&username, &password));
+
+        if allowed {
+            next.run(request)
+        } else {
+            Response::ok().status(401, "UNAUTHORIZED").header(
+                "WWW-Authenticate",
+                &format!("Basic realm=\"{}\"", self.realm),
+            )
+        }
+    }
+}
+
+// Decodes standard base64 (the alphabet with "+" and "/"), with or without the "=" padding
+// on the end. Returns None for anything that isn't valid base64
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    // Any leftover of a single character can't make up a whole byte
+    if encoded.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for &c in encoded {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        // Each character is 6 bits, so every time there's 8 or more saved up, that's a byte
+        bits = bits << 6 | u32::from(value);
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+
+    Some(decoded)
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+use crate::request::ParseError;
+
+/// The fields of a form sent with "Content-Type: multipart/form-data", which is what a browser
+/// sends for a form that has a file in it. The body is split up into parts by a boundary that
+/// the Content-Type gives, with each part having headers of its own, i.e.:
+/// ```text
+/// --XyZ
+/// Content-Disposition: form-data; name="title"
+///
+/// My holiday
+/// --XyZ
+/// Content-Disposition: form-data; name="photo"; filename="beach.jpg"
+/// Content-Type: image/jpeg
+///
+/// <the file's bytes>
+/// --XyZ--
+/// ```
+///
+/// The parts borrow their data from the request's body, so a big upload doesn't get copied.
+/// The body has already been read into memory by then, so it's max_body_size that keeps an
+/// upload from being bigger than we can handle
+pub struct Multipart<'a> {
+    /// Every part of the body, in the order they came in
+    pub parts: Vec<Part<'a>>,
+}
+
+impl<'a> Multipart<'a> {
+    /// Splits a body up into its parts, using the boundary from its Content-Type
+    pub fn parse(content_type: &str, body: &'a [u8]) -> Result<Multipart<'a>, ParseError> {
+        let malformed = |reason: &str| ParseError::MalformedBody(reason.to_string());
+
+        let (mime_type, params) = split_params(content_type);
+        if !mime_type.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(malformed("not multipart/form-data"));
+        }
+        let boundary = params
+            .get("boundary")
+            .filter(|boundary| !boundary.is_empty())
+            .ok_or_else(|| malformed("no boundary"))?;
+        let delimiter = format!("--{boundary}").into_bytes();
+        let next_delimiter = [b"\r\n".as_slice(), &delimiter].concat();
+
+        // Anything before the first boundary is a preamble, which is meant to be ignored
+        let mut rest = if body.starts_with(&delimiter) {
+            &body[delimiter.len()..]
+        } else {
+            let start = find(body, &next_delimiter).ok_or_else(|| malformed("no boundary"))?;
+            &body[start + next_delimiter.len()..]
+        };
+
+        let mut parts = Vec::new();
+        loop {
+            // The last boundary has "--" after it. Every other one ends its line, and
+            // then the next part starts
+            if rest.starts_with(b"--") {
+                return Ok(Multipart { parts });
+            }
+            rest = rest
+                .strip_prefix(b"\r\n")
+                .ok_or_else(|| malformed("boundary not followed by a new line"))?;
+
+            let end = find(rest, &next_delimiter).ok_or_else(|| malformed("no final boundary"))?;
+            parts.push(Part::parse(&rest[..end])?);
+            rest = &rest[end + next_delimiter.len()..];
+        }
+    }
+
+    /// The first part for the form field with the given name
+    pub fn get(&self, name: &str) -> Option<&Part<'a>> {
+        self.parts.iter().find(|part| part.name() == Some(name))
+    }
+}
+
+/// One field of a form, along with its headers (with their names in lowercase)
+pub struct Part<'a> {
+    /// The part's headers, by their names in lowercase, i.e.: "content-type"
+    pub headers: HashMap<String, String>,
+    /// Everything after the part's headers, up to the next boundary
+    pub data: &'a [u8],
+    // The parameters from the Content-Disposition header, i.e.: name => "photo" and
+    // filename => "beach.jpg"
+    disposition: HashMap<String, String>,
+}
+
+impl<'a> Part<'a> {
+    fn parse(part: &'a [u8]) -> Result<Part<'a>, ParseError> {
+        let malformed = |reason: &str| ParseError::MalformedBody(reason.to_string());
+
+        // A part with no headers starts straight away with the blank line after them
+        let (head, data) = match part.strip_prefix(b"\r\n") {
+            Some(data) => (&b""[..], data),
+            None => {
+                let end = find(part, b"\r\n\r\n").ok_or_else(|| malformed("unfinished headers"))?;
+                (&part[..end], &part[end + 4..])
+            }
+        };
+
+        let mut headers = HashMap::new();
+        let head = String::from_utf8_lossy(head);
+        for line in head.split("\r\n").filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| {
+                ParseError::MalformedBody(format!("malformed part header: {line:?}"))
+            })?;
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let disposition = headers
+            .get("content-disposition")
+            .map(|disposition| split_params(disposition).1)
+            .unwrap_or_default();
+
+        Ok(Part {
+            headers,
+            data,
+            disposition,
+        })
+    }
+
+    /// The name of the form field this part is for
+    pub fn name(&self) -> Option<&str> {
+        self.disposition.get("name").map(String::as_str)
+    }
+
+    /// The name of the file that was uploaded, if this part is a file
+    pub fn filename(&self) -> Option<&str> {
+        self.disposition.get("filename").map(String::as_str)
+    }
+
+    /// The type of the part's data, i.e.: "image/jpeg". Without one, it's plain text
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("content-type").map(String::as_str)
+    }
+}
+
+// Splits a header like 'form-data; name="photo"; filename="beach.jpg"' up into its value and
+// its parameters, with the quotes taken off of any that are quoted. A quoted parameter can
+// have semicolons of its own (i.e.: a filename like "a;b.txt"), so those don't split it up.
+// Parameter names are case-insensitive, so they come back in lowercase
+fn split_params(header: &str) -> (&str, HashMap<String, String>) {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in header.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                pieces.push(&header[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&header[start..]);
+
+    let value = pieces[0].trim();
+    let params = pieces[1..]
+        .iter()
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            (name.trim().to_ascii_lowercase(), value.to_string())
+        })
+        .collect();
+
+    (value, params)
+}
+
+// Where needle first shows up in haystack
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_a_form_into_its_field_and_its_file() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            My holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\
+            \r\n\
+            \xff\xd8\r\n--not the boundary\r\n\xff\xd9\r\n\
+            --XyZ--\r\n";
+
+        let form = Multipart::parse("multipart/form-data; boundary=XyZ", body).unwrap();
+        assert_eq!(form.parts.len(), 2);
+
+        let title = form.get("title").unwrap();
+        assert_eq!(title.data, b"My holiday");
+        assert_eq!(title.filename(), None);
+        assert_eq!(title.content_type(), None);
+
+        // The file's bytes come back just as they were, line endings and all
+        let photo = form.get("photo").unwrap();
+        assert_eq!(photo.filename(), Some("beach.jpg"));
+        assert_eq!(photo.content_type(), Some("image/jpeg"));
+        assert_eq!(photo.data, b"\xff\xd8\r\n--not the boundary\r\n\xff\xd9");
+    }
+}
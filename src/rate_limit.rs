@@ -0,0 +1,110 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+};
+
+// Limits how many requests each client (going by its IP address) can send us, i.e.: 100 per
+// minute. Anyone sending more than that gets a "429 Too Many Requests", along with how many
+// seconds to wait before trying again, so one client can't keep all of the pool's threads to
+// itself
+//
+// Each client has a bucket of tokens, which starts out full, with one token for each request
+// it's allowed per window. Every request takes a token out, and the bucket slowly fills back
+// up, at a rate that would fill it from empty over one window. So a client can send all of
+// its requests at once, but then has to wait for more tokens, instead of for the next window
+pub struct RateLimiter {
+    requests: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    // When the buckets were last cleaned up
+    last_cleanup: Mutex<Instant>,
+}
+
+struct Bucket {
+    tokens: f64,
+    // When the tokens were last worked out, which is how we know how many to add back
+    updated: Instant,
+}
+
+impl RateLimiter {
+    // Allows each client at most requests per window. There always has to be room
+    // for at least one request
+    pub fn new(requests: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            requests: requests.max(1),
+            window,
+            buckets: Mutex::new(HashMap::new()),
+            last_cleanup: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Takes a token out of the client's bucket. If there aren't any left, it gives back
+    // how long until there will be one instead
+    fn take(&self, client: IpAddr) -> Result<(), Duration> {
+        let capacity = f64::from(self.requests);
+        let per_second = capacity / self.window.as_secs_f64();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        self.clean_up(&mut buckets, now);
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    // Throws away the buckets of clients that haven't sent anything for a whole window.
+    // Their buckets would be full again by now, which is the same as not having one, so
+    // there's no point keeping them. This only happens once per window, so it isn't going
+    // through every bucket on every request
+    fn clean_up(&self, buckets: &mut HashMap<IpAddr, Bucket>, now: Instant) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+        if now.duration_since(*last_cleanup) < self.window {
+            return;
+        }
+
+        buckets.retain(|_, bucket| now.duration_since(bucket.updated) < self.window);
+        *last_cleanup = now;
+    }
+}
+
+// Requests without an IP address (i.e.: over a Unix socket) come from the same machine as the
+// server, so they're left alone
+impl Middleware for RateLimiter {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let Some(client) = request.client_ip else {
+            return next.run(request);
+        };
+
+        match self.take(client) {
+            Ok(()) => next.run(request),
+            Err(wait) => {
+                // Retry-After is in whole seconds, so round up, or the client would come back
+                // a moment too soon and get turned away again
+                let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                Response::ok()
+                    .status(429, "TOO MANY REQUESTS")
+                    .header("Retry-After", &seconds.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,926 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use crate::url;
+
+use crate::{multipart::Multipart, session::Session};
+
+// The id the next request gets, if its client didn't give it one of its own
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// The longest X-Request-Id we'll take from a client. Anything longer gets an id from us instead
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The methods a request can use. Anything else gets a 501 Not Implemented. They're ordered
+/// the way they're declared, which is the order they get listed in an Allow header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HttpMethod {
+    /// `GET`: fetch a resource
+    Get,
+    /// `POST`: send data for the resource to process
+    Post,
+    /// `PUT`: create or replace a resource
+    Put,
+    /// `DELETE`: remove a resource
+    Delete,
+    /// `PATCH`: change part of a resource
+    Patch,
+    /// `HEAD`: the same as `GET`, but only the headers come back
+    Head,
+    /// `OPTIONS`: ask which methods a resource allows
+    Options,
+}
+
+impl HttpMethod {
+    /// The method's name, the way it's written in a request line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Options => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Method names are case-sensitive, so "get" isn't the same thing as "GET"
+impl FromStr for HttpMethod {
+    type Err = ParseError;
+
+    fn from_str(method: &str) -> Result<HttpMethod, ParseError> {
+        match method {
+            "GET" => Ok(HttpMethod::Get),
+            "POST" => Ok(HttpMethod::Post),
+            "PUT" => Ok(HttpMethod::Put),
+            "DELETE" => Ok(HttpMethod::Delete),
+            "PATCH" => Ok(HttpMethod::Patch),
+            "HEAD" => Ok(HttpMethod::Head),
+            "OPTIONS" => Ok(HttpMethod::Options),
+            _ => Err(ParseError::UnknownMethod(method.to_string())),
+        }
+    }
+}
+
+/// The versions of HTTP we can talk. Anything else (i.e.: "HTTP/2.0", which a client can only
+/// use with a server that's said it understands it) gets a 505 HTTP Version Not Supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.0
+    Http10,
+    /// HTTP/1.1
+    Http11,
+}
+
+impl HttpVersion {
+    /// The version, the way it's written in a request line or a status line
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::Http10 => "HTTP/1.0",
+            HttpVersion::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for HttpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Something that isn't "HTTP/" and then a version number, i.e.: "HTTP/2.0", isn't a version
+// at all, which means the request line is malformed, rather than just asking for a version
+// we don't have
+impl FromStr for HttpVersion {
+    type Err = ParseError;
+
+    fn from_str(version: &str) -> Result<HttpVersion, ParseError> {
+        let is_number =
+            |digits: &str| !digits.is_empty() && digits.bytes().all(|digit| digit.is_ascii_digit());
+
+        match version {
+            "HTTP/1.0" => Ok(HttpVersion::Http10),
+            "HTTP/1.1" => Ok(HttpVersion::Http11),
+            _ => match version
+                .strip_prefix("HTTP/")
+                .and_then(|number| number.split_once('.'))
+            {
+                Some((major, minor)) if is_number(major) && is_number(minor) => {
+                    Err(ParseError::UnsupportedVersion(version.to_string()))
+                }
+                _ => Err(ParseError::MalformedRequestLine(version.to_string())),
+            },
+        }
+    }
+}
+
+/// What we know about a request that came in from a client, which is what handlers get
+///
+/// It borrows the connection it came in on for as long as it's around, since that's where
+/// the rest of the body is for a route that reads it itself (see body_reader)
+pub struct Request<'a> {
+    /// Tells this request apart from every other one, in the logs and in the X-Request-Id
+    /// header of its response. It's whatever the client sent in its own X-Request-Id header,
+    /// so a request can be followed through every server it passes through, or a number from
+    /// us if it didn't send one, i.e.: "42"
+    pub id: String,
+    /// What the request is asking for the path to do, i.e.: GET
+    pub method: HttpMethod,
+    /// Everything in the request's target before the "?", i.e.: "/search" for "/search?q=rust"
+    pub path: String,
+    /// The parameters from the query string, i.e.: q => "rust" for "/search?q=rust", after
+    /// they've been percent-decoded. If a parameter shows up more than once, the first one wins
+    pub query: HashMap<String, String>,
+    /// The query string just as the client sent it, i.e.: "q=rust" for "/search?q=rust", or
+    /// empty if there wasn't one
+    pub query_string: String,
+    /// The version of HTTP the request was sent with
+    pub version: HttpVersion,
+    /// Header names are case-insensitive, so they're stored in lowercase. A header that
+    /// shows up more than once has its values joined together with commas
+    pub headers: HashMap<String, String>,
+    /// The cookies from the Cookie header, i.e.: theme => "dark" for "Cookie: theme=dark; id=42".
+    /// Empty if the client didn't send any. If a cookie shows up more than once, the first one wins
+    pub cookies: HashMap<String, String>,
+    /// The parameters from the route that matched the path, i.e.: id => "42" for "/users/42"
+    /// with the route "/users/:id". These only get filled in once the Router has found the route
+    pub params: HashMap<String, String>,
+    /// The client's session, which gets filled in by the SessionStore middleware. It's set
+    /// through a shared reference, since that's all the middlewares get, which is why it's a
+    /// OnceLock rather than an Option
+    pub session: OnceLock<Session>,
+    /// The IP address and port of the client that sent the request, i.e.: 127.0.0.1:51234.
+    /// This gets filled in from the connection once the request has been read off of it, and
+    /// there isn't one for a request that came over a Unix socket
+    pub peer: Option<SocketAddr>,
+    /// The IP address of the client the request is really from. That's the peer's, unless
+    /// we're behind a proxy we trust, in which case it's whichever client the proxy says it
+    /// passed the request on for (see forwarded_for)
+    pub client_ip: Option<IpAddr>,
+    /// The whole body, once it's been read with read_body. It's empty for a route that
+    /// reads the body itself (see Router::post_streaming)
+    pub body: Vec<u8>,
+    // Whatever's left of the body on the connection, which is all of it until it gets read.
+    // It's behind a Mutex for the same reason session is in a OnceLock
+    body_reader: Mutex<BodyReader<'a>>,
+}
+
+impl<'a> Request<'a> {
+    /// Looks up a header's value, ignoring the case of its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Looks up a query string parameter's value
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    /// Looks up a cookie's value
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(String::as_str)
+    }
+
+    /// Looks up one of the route's parameters, i.e.: param("id") for the route "/users/:id"
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The client that a proxy says it passed the request on for, from the first address in
+    /// X-Forwarded-For (which is the one the client itself connected to the first proxy from),
+    /// or from X-Real-IP. Anyone can send these headers, so they're only worth believing when
+    /// the request really did come through a proxy, which replaces whatever the client sent
+    pub fn forwarded_for(&self) -> Option<IpAddr> {
+        let forwarded = self
+            .header("X-Forwarded-For")
+            .and_then(|addresses| addresses.split(',').next());
+        forwarded
+            .or_else(|| self.header("X-Real-IP"))
+            .and_then(|address| address.trim().parse().ok())
+    }
+
+    /// The fields of a form sent with "Content-Type: multipart/form-data", which is how files
+    /// get uploaded
+    pub fn multipart(&self) -> Result<Multipart<'_>, ParseError> {
+        let content_type = self
+            .header("Content-Type")
+            .ok_or_else(|| ParseError::MalformedBody("no Content-Type".to_string()))?;
+        Multipart::parse(content_type, &self.body)
+    }
+
+    /// The client's session, for remembering things about it between requests. There's only
+    /// a session if the request went through the SessionStore middleware, so there isn't one
+    /// for a route that skips the middlewares
+    pub fn session(&self) -> Option<&Session> {
+        self.session.get()
+    }
+
+    /// The fields of a submitted HTML form, from a body sent with "Content-Type:
+    /// application/x-www-form-urlencoded" (which is encoded just like a query string, i.e.:
+    /// "name=Jane+Doe&age=42"). A body of any other type doesn't have any form fields
+    pub fn form(&self) -> HashMap<String, String> {
+        // The type can have parameters after it, i.e.: "; charset=UTF-8"
+        let is_form = self.header("Content-Type").is_some_and(|content_type| {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        });
+
+        if is_form {
+            parse_query(&String::from_utf8_lossy(&self.body))
+        } else {
+            HashMap::new()
+        }
+    }
+
+    /// Reads the whole body into body, unless it's bigger than we allow, in which case none
+    /// of it gets read, so the client can't make us allocate it either
+    pub fn read_body(&mut self, limits: &RequestLimits) -> Result<(), ParseError> {
+        let length = self.check_body_size(limits)?;
+        let mut body = vec![0; length];
+        self.body_reader.get_mut().unwrap().read_exact(&mut body)?;
+        self.body = body;
+        Ok(())
+    }
+
+    /// How long the body is, as long as it isn't bigger than we allow
+    pub fn check_body_size(&self, limits: &RequestLimits) -> Result<usize, ParseError> {
+        let length = usize::try_from(self.body_reader().remaining()).unwrap_or(usize::MAX);
+        if length > limits.max_body_size {
+            return Err(ParseError::BodyTooLarge(length));
+        }
+        Ok(length)
+    }
+
+    /// The body, for a handler to read as it comes in off of the connection, instead of all
+    /// at once, i.e.: to write a big upload straight out to a file. Once the body has been
+    /// read (whether that's by read_body or by an earlier call to this), there's nothing left
+    /// in it to read
+    pub fn body_reader(&self) -> MutexGuard<'_, BodyReader<'a>> {
+        self.body_reader.lock().unwrap()
+    }
+
+    /// Whether the client is waiting for a "100 Continue" before it sends the body, i.e.: one
+    /// with a big upload, which would rather find out it's going to be turned away before
+    /// sending all of it. Only HTTP/1.1 clients know to wait for one
+    pub fn expects_continue(&self) -> bool {
+        self.version == HttpVersion::Http11
+            && self
+                .header("Expect")
+                .is_some_and(|expect| expect.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the client wants to keep the connection open for another request after this
+    /// one. HTTP/1.1 connections stay open unless the client says "Connection: close", and
+    /// older ones only stay open if the client asks for it with "Connection: keep-alive"
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.header("Connection").is_some_and(|connection| {
+                connection
+                    .split(',')
+                    .any(|value| value.trim().eq_ignore_ascii_case(option))
+            })
+        };
+
+        if self.version == HttpVersion::Http11 {
+            !has_option("close")
+        } else {
+            has_option("keep-alive")
+        }
+    }
+}
+
+/// A request's body, read straight off of the connection. It gives back exactly as many
+/// bytes as the Content-Length said there would be, and then stops, as if that was the end
+/// of the connection, so anything after them is left alone for the connection's next request
+pub struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead,
+    // How many bytes of the body haven't been read yet
+    remaining: u64,
+}
+
+impl<'a> BodyReader<'a> {
+    fn new(reader: &'a mut dyn BufRead, length: u64) -> BodyReader<'a> {
+        BodyReader {
+            reader,
+            remaining: length,
+        }
+    }
+
+    /// How much of the body is still waiting to be read
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for BodyReader<'_> {
+    // The client hanging up before it has sent the whole body is an error, rather than the
+    // end of the body, so a handler can't mistake half an upload for all of it
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.remaining == 0 {
+            return Ok(&[]);
+        }
+
+        let available = self.reader.fill_buf()?;
+        if available.is_empty() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let length = available
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        Ok(&available[..length])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+        self.remaining -= amount as u64;
+    }
+}
+
+/// The most a client is allowed to send us, so one that sends a huge request
+/// (by accident or on purpose) can't run the server out of memory
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// The largest body we'll accept, going by its Content-Length
+    pub max_body_size: usize,
+    /// The most bytes the request line and headers can add up to, line endings included
+    pub max_header_size: usize,
+    /// The most bytes the request line can be on its own, line ending included. It's almost
+    /// all URI, so a request line that's longer than this gets a 414, rather than a 431
+    pub max_request_line: usize,
+}
+
+impl Default for RequestLimits {
+    // 1 MiB of body, 8 KiB of headers, and 4 KiB of that for the request line, which is
+    // plenty for anything this server does
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_body_size: 1024 * 1024,
+            max_header_size: 8 * 1024,
+            max_request_line: 4 * 1024,
+        }
+    }
+}
+
+/// The ways reading a request off of a connection can go wrong
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client closed the connection before sending a request line
+    ConnectionClosed,
+    /// The first line wasn't in the form "Method Uri HttpVersion"
+    MalformedRequestLine(String),
+    /// The request used a method we don't support
+    UnknownMethod(String),
+    /// The request used a version of HTTP we don't support, i.e.: "HTTP/2.0"
+    UnsupportedVersion(String),
+    /// A header line didn't have a colon separating the name from the value. It comes with the
+    /// line's number in the request, counting the request line as line 1, i.e.: 3 for the
+    /// second header
+    MalformedHeader(usize, String),
+    /// The Content-Length header wasn't a number
+    InvalidContentLength(String),
+    /// The request came with a Transfer-Encoding, i.e.: "chunked", which we don't read bodies
+    /// in. Going by the Content-Length (or the lack of one) instead would mean reading the
+    /// body as the start of the next request
+    UnsupportedTransferEncoding(String),
+    /// The request came with both a Transfer-Encoding and a Content-Length, so there's no
+    /// telling which of them the client (or a proxy in front of us) went by
+    ConflictingBodyLength,
+    /// The body wasn't what its Content-Type said it was, i.e.: a multipart body without
+    /// its final boundary
+    MalformedBody(String),
+    /// The request line was longer than RequestLimits::max_request_line
+    UriTooLong,
+    /// The request line and headers were longer than RequestLimits::max_header_size
+    HeadersTooLarge,
+    /// The Content-Length was bigger than RequestLimits::max_body_size
+    BodyTooLarge(usize),
+    /// The client took too long to send the request
+    TimedOut,
+    /// The client closed the connection partway through the request, after its request line
+    UnexpectedEof,
+    /// Reading from the connection failed, or it closed partway through the request
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// The status to tell the client about the error with: 501 for a method or a
+    /// Transfer-Encoding we don't support, 505 for a version of HTTP we don't support, 408 for a client that was too slow, 413,
+    /// 414, or 431 for a request that's too big, and 400 for anything else wrong with it. A
+    /// client that's closed the connection never gets to see its status, but it still has one
+    pub fn status(&self) -> (u16, &'static str) {
+        match self {
+            ParseError::UnknownMethod(_) | ParseError::UnsupportedTransferEncoding(_) => {
+                (501, "NOT IMPLEMENTED")
+            }
+            ParseError::UnsupportedVersion(_) => (505, "HTTP VERSION NOT SUPPORTED"),
+            ParseError::TimedOut => (408, "REQUEST TIMEOUT"),
+            ParseError::BodyTooLarge(_) => (413, "PAYLOAD TOO LARGE"),
+            ParseError::UriTooLong => (414, "URI TOO LONG"),
+            ParseError::HeadersTooLarge => (431, "REQUEST HEADER FIELDS TOO LARGE"),
+            ParseError::ConnectionClosed
+            | ParseError::MalformedRequestLine(_)
+            | ParseError::MalformedHeader(..)
+            | ParseError::InvalidContentLength(_)
+            | ParseError::ConflictingBodyLength
+            | ParseError::MalformedBody(_)
+            | ParseError::UnexpectedEof
+            | ParseError::Io(_) => (400, "BAD REQUEST"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::ConnectionClosed => {
+                write!(f, "the connection closed before a request was sent")
+            }
+            ParseError::MalformedRequestLine(line) => write!(f, "malformed request line: {line:?}"),
+            ParseError::UnknownMethod(method) => write!(f, "unknown method: {method:?}"),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported HTTP version: {version:?}")
+            }
+            ParseError::MalformedHeader(number, line) => {
+                write!(f, "malformed header on line {number}: {line:?}")
+            }
+            ParseError::InvalidContentLength(value) => {
+                write!(f, "invalid Content-Length: {value:?}")
+            }
+            ParseError::UnsupportedTransferEncoding(encoding) => {
+                write!(f, "unsupported Transfer-Encoding: {encoding:?}")
+            }
+            ParseError::ConflictingBodyLength => {
+                write!(f, "both a Transfer-Encoding and a Content-Length were sent")
+            }
+            ParseError::MalformedBody(reason) => write!(f, "malformed body: {reason}"),
+            ParseError::UriTooLong => write!(f, "the request line is too long"),
+            ParseError::HeadersTooLarge => write!(f, "the request's headers are too large"),
+            ParseError::BodyTooLarge(length) => {
+                write!(f, "the request's body is too large ({length} bytes)")
+            }
+            ParseError::TimedOut => write!(f, "timed out waiting for the request"),
+            ParseError::UnexpectedEof => {
+                write!(f, "the connection closed partway through the request")
+            }
+            ParseError::Io(err) => write!(f, "failed to read the request: {err}"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// A read that runs past the stream's read timeout fails with WouldBlock on some platforms, and
+// TimedOut on others, so both mean the client was too slow. A read that needed more than the
+// client sent before closing the connection (i.e.: of a body that's shorter than its
+// Content-Length) fails with UnexpectedEof
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::TimedOut,
+            io::ErrorKind::UnexpectedEof => ParseError::UnexpectedEof,
+            _ => ParseError::Io(err),
+        }
+    }
+}
+
+/// Reads a request off of the reader, which will be in the form:
+/// ```text
+/// "Method Uri HttpVersion"  -> i.e.: "GET / HTTP/1.1"
+/// "Name: Value"             -> one line for each header
+/// ""                        -> a blank line, marking the end of the headers
+/// body                      -> exactly Content-Length bytes (if there's a body at all)
+/// ```
+/// A request line or headers bigger than the limits allow are turned down before we've read
+/// all of them, so a client that sends one that never ends (i.e.: without a newline) can't get
+/// us to hold on to all of it. The body is left on the reader, for read_body (or the handler)
+/// to read
+pub fn parse_request<'a, R: BufRead>(
+    reader: &'a mut R,
+    limits: &RequestLimits,
+) -> Result<Request<'a>, ParseError> {
+    // How much more of the request line and headers we're willing to read
+    let mut header_budget = limits.max_header_size;
+
+    // The request line has a limit of its own, and counts towards the headers' too
+    let mut line_budget = limits.max_request_line;
+    let request_line = match read_line(reader, &mut line_budget) {
+        Ok(Some(line)) => line,
+        Ok(None) => return Err(ParseError::ConnectionClosed),
+        Err(ParseError::HeadersTooLarge) => return Err(ParseError::UriTooLong),
+        Err(err) => return Err(err),
+    };
+    let line_len = limits.max_request_line - line_budget;
+    if line_len > header_budget {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    header_budget -= line_len;
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::MalformedRequestLine(request_line));
+    };
+    let method = method.parse::<HttpMethod>()?;
+    let (path, query_string) = match path.split_once('?') {
+        Some((path, query_string)) => (path.to_string(), query_string.to_string()),
+        None => (path.to_string(), String::new()),
+    };
+    let query = parse_query(&query_string);
+    let version = match version.parse::<HttpVersion>() {
+        Ok(version) => version,
+        Err(ParseError::MalformedRequestLine(_)) => {
+            return Err(ParseError::MalformedRequestLine(request_line))
+        }
+        Err(err) => return Err(err),
+    };
+
+    // Keep reading headers until we get to the blank line. The request line was line 1
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for number in 2.. {
+        let line = match read_line(reader, &mut header_budget)? {
+            Some(line) => line,
+            None => return Err(ParseError::UnexpectedEof),
+        };
+        if line.is_empty() {
+            break;
+        }
+
+        // There can't be any whitespace in a name, including between it and the colon, since
+        // a proxy in front of us might not read "Name : value" as the same header we would
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::MalformedHeader(number, line));
+        };
+        if name.is_empty() || name.bytes().any(|byte| byte.is_ascii_whitespace()) {
+            return Err(ParseError::MalformedHeader(number, line));
+        }
+        let name = name.to_ascii_lowercase();
+        let value = value.trim();
+
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    let id = request_id(headers.get("x-request-id"));
+
+    let cookies = headers
+        .get("cookie")
+        .map(|cookie| parse_cookies(cookie))
+        .unwrap_or_default();
+
+    // We only ever read a body by its Content-Length, so a request that's sent its body some
+    // other way has to be turned away, along with the rest of the connection, or that body
+    // would get read as the next request (which is how one request gets smuggled inside
+    // another, past a proxy that did read it the other way)
+    if let Some(encoding) = headers.get("transfer-encoding") {
+        if headers.contains_key("content-length") {
+            return Err(ParseError::ConflictingBodyLength);
+        }
+        return Err(ParseError::UnsupportedTransferEncoding(encoding.clone()));
+    }
+
+    // The body is exactly as many bytes as the client said it has
+    let length = match headers.get("content-length") {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| ParseError::InvalidContentLength(value.clone()))?,
+        None => 0,
+    };
+
+    Ok(Request {
+        id,
+        method,
+        path,
+        query,
+        query_string,
+        version,
+        headers,
+        cookies,
+        params: HashMap::new(),
+        session: OnceLock::new(),
+        peer: None,
+        client_ip: None,
+        body: Vec::new(),
+        body_reader: Mutex::new(BodyReader::new(reader, length)),
+    })
+}
+
+// The id for a request that came with the given X-Request-Id header (if any). The client's id
+// ends up in our logs and our response, so it's only used if it's a sensible length, and made
+// of nothing but visible ASCII, which makes it impossible to break up a log line with it
+fn request_id(client_id: Option<&String>) -> String {
+    match client_id {
+        Some(id)
+            if !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|byte| byte.is_ascii_graphic()) =>
+        {
+            id.clone()
+        }
+        // Nothing else depends on the ids being in order, just on them all being different
+        _ => NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string(),
+    }
+}
+
+// Reads one line, without its line ending ("\r\n", or just "\n" from a lenient client), and
+// takes its length off of budget. Returns None if the connection was closed before anything
+// was read, or HeadersTooLarge if the line doesn't fit in what's left of the budget
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    // Never read more than one byte past the budget, which is enough to tell a line
+    // that's too long apart from one that just fits, without buffering the whole thing
+    let mut line = String::new();
+    let limit = u64::try_from(*budget).unwrap_or(u64::MAX).saturating_add(1);
+    let read = reader.by_ref().take(limit).read_line(&mut line)?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    *budget -= read;
+
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(Some(line))
+}
+
+// Splits a query string like "q=rust+lang&page=2" into its parameters. A parameter without
+// an "=" gets an empty value
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params
+            .entry(form_decode(name))
+            .or_insert_with(|| form_decode(value));
+    }
+
+    params
+}
+
+// Splits a Cookie header like "theme=dark; id=42" into its cookies. Only the first "=" in each
+// pair separates the name from the value, since the value can have "="s of its own (base64
+// does, i.e.: "token=YWJj=="). A pair without an "=" isn't a cookie, so it gets skipped
+fn parse_cookies(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for pair in header.split(';') {
+        if let Some((name, value)) = pair.split_once('=') {
+            cookies
+                .entry(name.trim().to_string())
+                .or_insert_with(|| value.trim().to_string());
+        }
+    }
+
+    cookies
+}
+
+// A name or value from a query string (or a form), with any bad "%" escapes in it left
+// just as they were sent, since one mistyped parameter isn't worth failing the request over
+fn form_decode(encoded: &str) -> String {
+    url::decode_form(encoded).unwrap_or_else(|_| encoded.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use super::*;
+
+    // Small enough that the requests in these tests can run past each of them
+    const LIMITS: RequestLimits = RequestLimits {
+        max_body_size: 16,
+        max_header_size: 128,
+        max_request_line: 64,
+    };
+
+    // Reads a whole request off of reader, body and all, the way the server does
+    fn read<R: BufRead>(reader: &mut R) -> Result<Request<'_>, ParseError> {
+        let mut request = parse_request(reader, &LIMITS)?;
+        request.read_body(&LIMITS)?;
+        Ok(request)
+    }
+
+    // What's wrong with raw, which should be turned down
+    fn parse_error(raw: &str) -> ParseError {
+        let mut reader = Cursor::new(raw.as_bytes());
+        match read(&mut reader) {
+            Ok(request) => panic!("{raw:?} was parsed as a {} request", request.method),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn turns_away_each_kind_of_malformed_request() {
+        let long_path = format!("/{}", "a".repeat(64));
+        let long_header = format!("X-Padding: {}", "a".repeat(128));
+        // What's sent, which ParseError it should get, and the status that goes with it
+        let cases = [
+            ("", "ConnectionClosed", 400),
+            ("GET /\r\n\r\n", "MalformedRequestLine", 400),
+            ("GET / HTTP/1.1 extra\r\n\r\n", "MalformedRequestLine", 400),
+            ("BREW / HTTP/1.1\r\n\r\n", "UnknownMethod", 501),
+            ("get / HTTP/1.1\r\n\r\n", "UnknownMethod", 501),
+            ("GET / HTTP/2.0\r\n\r\n", "UnsupportedVersion", 505),
+            ("GET / HTTX/1.1\r\n\r\n", "MalformedRequestLine", 400),
+            (
+                "GET / HTTP/1.1\r\nNo colon here\r\n\r\n",
+                "MalformedHeader",
+                400,
+            ),
+            (
+                "GET / HTTP/1.1\r\nContent-Length: five\r\n\r\n",
+                "InvalidContentLength",
+                400,
+            ),
+            (
+                "GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+                "InvalidContentLength",
+                400,
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+                "UnsupportedTransferEncoding",
+                501,
+            ),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n",
+                "ConflictingBodyLength",
+                400,
+            ),
+            (
+                &format!("GET {long_path} HTTP/1.1\r\n\r\n"),
+                "UriTooLong",
+                414,
+            ),
+            (
+                &format!("GET / HTTP/1.1\r\n{long_header}\r\n\r\n"),
+                "HeadersTooLarge",
+                431,
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n",
+                "BodyTooLarge",
+                413,
+            ),
+            (
+                "GET / HTTP/1.1\r\nHost: localhost\r\n",
+                "UnexpectedEof",
+                400,
+            ),
+            (
+                "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi",
+                "UnexpectedEof",
+                400,
+            ),
+        ];
+
+        for (raw, expected, status) in cases {
+            let err = parse_error(raw);
+            assert!(
+                format!("{err:?}").starts_with(expected),
+                "{raw:?} should be {expected}, not {err:?}"
+            );
+            assert_eq!(err.status().0, status, "{raw:?}");
+        }
+    }
+
+    // A client that's sent the start of a request, and then nothing more, so the read after
+    // that runs past its timeout
+    struct Stalled(Cursor<&'static [u8]>);
+
+    impl Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[test]
+    fn gives_up_on_a_client_that_stops_partway_through() {
+        let stalled = [
+            &b"GET / HT"[..],
+            b"GET / HTTP/1.1\r\nHost: local",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhe",
+        ];
+        for raw in stalled {
+            let mut reader = BufReader::new(Stalled(Cursor::new(raw)));
+            let err = read(&mut reader).err();
+            assert!(
+                matches!(err, Some(ParseError::TimedOut)),
+                "{:?} should time out, not {err:?}",
+                String::from_utf8_lossy(raw)
+            );
+            assert_eq!(err.map(|err| err.status().0), Some(408));
+        }
+    }
+
+    #[test]
+    fn turns_away_a_request_with_a_transfer_encoding() {
+        let err = parse_error(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        );
+        assert!(
+            matches!(&err, ParseError::UnsupportedTransferEncoding(encoding) if encoding == "chunked")
+        );
+        assert_eq!(err.status().0, 501);
+
+        let err = parse_error(
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\nhello",
+        );
+        assert!(matches!(err, ParseError::ConflictingBodyLength));
+        assert_eq!(err.status().0, 400);
+    }
+
+    #[test]
+    fn turns_away_whitespace_before_a_headers_colon() {
+        let err = parse_error("GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length : 5\r\n\r\n");
+        assert!(matches!(err, ParseError::MalformedHeader(3, _)));
+
+        let err = parse_error("GET / HTTP/1.1\r\n Host: localhost\r\n\r\n");
+        assert!(matches!(err, ParseError::MalformedHeader(2, _)));
+    }
+
+    #[test]
+    fn stops_reading_the_body_at_its_content_length() {
+        let raw =
+            "POST /upload HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /next HTTP/1.1\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes());
+
+        let request = parse_request(&mut reader, &LIMITS).unwrap();
+        let mut body = Vec::new();
+        request.body_reader().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"hello");
+
+        // Anything past the end of the body is the next request's, so reading more gets nothing
+        let mut more = [0; 8];
+        assert_eq!(request.body_reader().read(&mut more).unwrap(), 0);
+        assert_eq!(request.body_reader().remaining(), 0);
+        drop(request);
+
+        let next = parse_request(&mut reader, &LIMITS).unwrap();
+        assert_eq!(next.path, "/next");
+    }
+
+    #[test]
+    fn fails_to_read_a_body_the_client_hung_up_partway_through() {
+        let mut reader =
+            Cursor::new(&b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"[..]);
+
+        let request = parse_request(&mut reader, &LIMITS).unwrap();
+        let mut body = Vec::new();
+        let err = request.body_reader().read_to_end(&mut body).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(body, b"abc");
+    }
+}
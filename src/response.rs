@@ -0,0 +1,633 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::{request::HttpVersion, template};
+
+/// What a handler sends back for a request. Built up a piece at a time, i.e.:
+/// ```text
+/// Response::ok().header("Content-Type", "text/html").body(contents)
+/// ```
+pub struct Response {
+    // The version of HTTP the status line says we're using, which is the one the client's
+    // request used, so a client that only knows HTTP/1.0 gets an HTTP/1.0 response
+    version: HttpVersion,
+    status: u16,
+    reason: String,
+    // Kept in the order they were added, and a header can show up more than once
+    headers: Vec<(String, String)>,
+    body: Body,
+    // Headers that go out after the last chunk of a chunked body, since they can't be worked
+    // out until all of it has been sent
+    trailers: Option<Trailers>,
+    // What to run once the response has been sent (see on_sent)
+    on_sent: Vec<OnSent>,
+}
+
+type Trailers = Box<dyn FnOnce() -> Vec<(String, String)> + Send>;
+type OnSent = Box<dyn FnOnce(Transferred) + Send>;
+
+/// How many bytes went over the connection each way for a request and its response. That's
+/// everything that was sent for them, i.e.: the request line and headers as well as its body,
+/// and the response's headers as well as its body, the way it was sent (compressed, or in
+/// chunks). Over TLS, it's the bytes before they were encrypted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transferred {
+    /// The bytes read for the request
+    pub received: u64,
+    /// The bytes written for the response
+    pub sent: u64,
+}
+
+// A body is either already in memory, or read from somewhere (i.e.: a file) a chunk at a time
+// as it gets sent, so that sending something huge doesn't mean holding all of it at once
+enum Body {
+    Bytes(Vec<u8>),
+    // We still have to know how long it is up front, for the Content-Length
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    // A body we don't know the length of until it ends, i.e.: one that's made up as it's sent.
+    // It goes out using "Transfer-Encoding: chunked", where each chunk says how long it is,
+    // and the end of the body is a chunk that's empty
+    Chunked(Box<dyn Read + Send>),
+}
+
+// The size of the chunks bodies are read in as they're sent
+const CHUNK_SIZE: usize = 8 * 1024;
+
+impl Response {
+    /// A "200 OK" response with no headers and an empty body
+    pub fn ok() -> Response {
+        Response {
+            version: HttpVersion::Http11,
+            status: 200,
+            reason: "OK".to_string(),
+            headers: Vec::new(),
+            body: Body::Bytes(Vec::new()),
+            trailers: None,
+            on_sent: Vec::new(),
+        }
+    }
+
+    /// A "200 OK" response whose body is read from reader as it's written, i.e.: a subprocess'
+    /// output, so it never has to be in memory all at once. With a content_length, the body is
+    /// sent just like body_reader sends it, and without one, in chunks, like body_stream
+    pub fn from_reader(
+        reader: impl Read + Send + 'static,
+        content_length: Option<u64>,
+    ) -> Response {
+        match content_length {
+            Some(len) => Response::ok().body_reader(reader, len),
+            None => Response::ok().body_stream(reader),
+        }
+    }
+
+    /// A redirect to location, either a "301 Moved Permanently" (which browsers remember, and
+    /// go straight to location from then on) or a "302 Found" (which they ask about again
+    /// next time). Either way, a browser follows it with a GET, whatever the method was, so
+    /// use redirect_preserve_method for a form that has to be posted to somewhere else
+    ///
+    /// It comes with a little page linking to location, for a client that doesn't follow it
+    pub fn redirect(location: &str, permanent: bool) -> Response {
+        let (code, reason) = if permanent {
+            (301, "MOVED PERMANENTLY")
+        } else {
+            (302, "FOUND")
+        };
+        Response::redirect_with(location, code, reason)
+    }
+
+    /// The same as redirect, except the client has to send the same request to location, with
+    /// the same method and body, i.e.: a POST stays a POST. That's a "308 Permanent Redirect"
+    /// or a "307 Temporary Redirect"
+    pub fn redirect_preserve_method(location: &str, permanent: bool) -> Response {
+        let (code, reason) = if permanent {
+            (308, "PERMANENT REDIRECT")
+        } else {
+            (307, "TEMPORARY REDIRECT")
+        };
+        Response::redirect_with(location, code, reason)
+    }
+
+    fn redirect_with(location: &str, code: u16, reason: &str) -> Response {
+        let href = template::escape(location);
+        Response::ok()
+            .status(code, reason)
+            .header("Location", location)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(format!(
+                "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+                 <title>Moved</title>\n</head>\n<body>\n\
+                 <p>This has moved to <a href=\"{href}\">{href}</a>.</p>\n</body>\n</html>\n"
+            ))
+    }
+
+    /// Sets the status code and the reason phrase that goes with it, i.e.: (404, "NOT FOUND")
+    pub fn status(mut self, code: u16, reason: &str) -> Response {
+        self.status = code;
+        self.reason = reason.to_string();
+        self
+    }
+
+    /// Sets the version of HTTP for the status line, which starts out as HTTP/1.1
+    pub fn version(mut self, version: HttpVersion) -> Response {
+        self.version = version;
+        self
+    }
+
+    /// Adds a header. Content-Length gets added automatically when the response is
+    /// written, so there's no need to set it, unless you need it to be something else
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds each of headers that the response doesn't already have one of, so whatever the
+    /// handler set takes priority over them
+    pub fn default_headers(mut self, headers: &[(String, String)]) -> Response {
+        for (name, value) in headers {
+            if self.header_value(name).is_none() {
+                self.headers.push((name.clone(), value.clone()));
+            }
+        }
+        self
+    }
+
+    /// Adds a Set-Cookie header, telling the client to send the cookie back with its
+    /// requests from now on. Each cookie gets its own header, so this can be called as many
+    /// times as there are cookies to set
+    pub fn set_cookie(self, name: &str, value: &str, options: &CookieOptions) -> Response {
+        let mut cookie = format!("{name}={value}");
+        if let Some(max_age) = options.max_age {
+            let _ = write!(cookie, "; Max-Age={max_age}");
+        }
+        if let Some(path) = &options.path {
+            let _ = write!(cookie, "; Path={path}");
+        }
+        if options.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if options.secure {
+            cookie.push_str("; Secure");
+        }
+        if let Some(same_site) = options.same_site {
+            let _ = write!(cookie, "; SameSite={}", same_site.as_str());
+        }
+
+        self.header("Set-Cookie", &cookie)
+    }
+
+    /// Sets the body, which can be text (a String or &str) or binary data (a `Vec<u8>` or `&[u8]`)
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Sets the body to everything reader has, however much that turns out to be. It gets read
+    /// as the response is written, and each read goes out as a chunk of its own
+    pub fn body_stream(mut self, reader: impl Read + Send + 'static) -> Response {
+        self.body = Body::Chunked(Box::new(reader));
+        self
+    }
+
+    /// Sets the body to the chunks, one after another, i.e.: from a handler that works out its
+    /// body a piece at a time. Each chunk gets sent as soon as it's been made, so the client
+    /// can start on it without waiting for the rest
+    pub fn body_chunks<I>(self, chunks: I) -> Response
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body_stream(ChunkReader {
+            chunks: chunks.into_iter(),
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Sends the headers that trailers gives back after the last chunk of the body, for ones
+    /// that can only be worked out once all of it has been sent, i.e.: a checksum of it. names
+    /// are the headers it'll give back, which the Trailer header tells the client to expect.
+    /// Only a body that's sent in chunks (see body_stream) can have trailers, so a client
+    /// older than HTTP/1.1 doesn't get them, and one that doesn't want them just ignores them
+    pub fn trailers<F>(mut self, names: &[&str], trailers: F) -> Response
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        self.trailers = Some(Box::new(trailers));
+        self.header("Trailer", &names.join(", "))
+    }
+
+    /// Runs sent once the response has been written to the client (or has failed to be, i.e.:
+    /// because the client went away), with how many bytes went each way for it. That can't
+    /// be known any sooner, since the body may still get compressed, or sent in chunks
+    pub fn on_sent<F>(mut self, sent: F) -> Response
+    where
+        F: FnOnce(Transferred) + Send + 'static,
+    {
+        self.on_sent.push(Box::new(sent));
+        self
+    }
+
+    /// Takes everything on_sent was given, for whoever writes the response to run once it has
+    pub fn take_on_sent(&mut self) -> impl FnOnce(Transferred) {
+        let on_sent = std::mem::take(&mut self.on_sent);
+        move |transferred| {
+            for sent in on_sent {
+                sent(transferred);
+            }
+        }
+    }
+
+    /// Sets the body to the len bytes that reader has, which don't get read until the
+    /// response is written, and then only a chunk at a time
+    pub fn body_reader(mut self, reader: impl Read + Send + 'static, len: u64) -> Response {
+        self.body = Body::Reader {
+            reader: Box::new(reader),
+            len,
+        };
+        self
+    }
+
+    /// Sets the body to value, serialized as JSON. If it can't be serialized (i.e.: it's a map
+    /// with keys that aren't strings), that's a bug on our end, so the client gets a 500
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(json) => Response::ok()
+                .header("Content-Type", "application/json")
+                .body(json),
+            Err(err) => {
+                eprintln!("Unable to serialize the response as JSON: {err}");
+                Response::ok().status(500, "INTERNAL SERVER ERROR")
+            }
+        }
+    }
+
+    /// Sets the body to the HTML page at path, with its placeholders filled in from vars (see
+    /// template::render). If the page can't be read (i.e.: it was deleted while the server was
+    /// running), that's our problem, not the client's, so they get a 500 instead
+    pub fn html_template(path: impl AsRef<Path>, vars: &HashMap<&str, String>) -> Response {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(page) => Response::ok()
+                .header("Content-Type", "text/html")
+                .body(template::render(&page, vars)),
+            Err(err) => {
+                eprintln!("Unable to read {}: {err}", path.display());
+                Response::ok().status(500, "INTERNAL SERVER ERROR")
+            }
+        }
+    }
+
+    /// The response's status code, i.e.: 200
+    pub fn status_code(&self) -> u16 {
+        self.status
+    }
+
+    /// The reason phrase that goes with the status code, i.e.: "OK"
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Looks up the value of a header that's been added, ignoring the case of its name.
+    /// If the header has been added more than once, this is the first one
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Makes the ETag (if there is one) weak, for a body that's been changed on its way out,
+    // i.e.: compressed, so it no longer promises to be the exact bytes the tag was made for.
+    // A weak ETag still works for If-None-Match, but never for If-Range
+    #[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+    pub(crate) fn weaken_etag(mut self) -> Response {
+        for (name, value) in &mut self.headers {
+            if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                value.insert_str(0, "W/");
+            }
+        }
+        self
+    }
+
+    /// The body, as it'll be sent, if it's in memory. A body that's read as it's sent
+    /// isn't available until then
+    pub fn contents(&self) -> Option<&[u8]> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Reader { .. } | Body::Chunked(_) => None,
+        }
+    }
+
+    /// How many bytes long the body is, if we know yet
+    pub fn body_len(&self) -> Option<u64> {
+        match &self.body {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => Some(*len),
+            Body::Chunked(_) => None,
+        }
+    }
+
+    /// Writes the whole response: the status line, the headers, a blank line, and then the body
+    ///   Response: "HttpVersion StatusCode Reason-Phrase\r\n headers\r\n\r\n response-body"
+    ///   Example:  "HTTP/1.1 200 OK\r\n\r\n" = Ok Response with no response body
+    /// A body that's in memory goes out in the same write as the head, so the client isn't
+    /// left waiting on the headers for the body to catch up. One that isn't gets copied over
+    /// a chunk at a time after the head, so it never has to all be in memory
+    pub fn write_to<W: Write>(self, stream: &mut W) -> io::Result<()> {
+        let mut bytes = self.head(true).into_bytes();
+        match self.body {
+            Body::Bytes(body) => {
+                bytes.extend_from_slice(&body);
+                stream.write_all(&bytes)
+            }
+            Body::Reader { reader, len } => {
+                stream.write_all(&bytes)?;
+
+                // If the reader runs out early (i.e.: the file got shorter after we looked at
+                // its length), the client would be left waiting on the rest of the body
+                // forever, so it's an error that should close the connection
+                let copied = io::copy(&mut reader.take(len), stream)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("the body ended after {copied} of its {len} bytes"),
+                    ));
+                }
+                Ok(())
+            }
+            Body::Chunked(mut reader) => {
+                stream.write_all(&bytes)?;
+
+                // Each chunk is its length in hex, then the chunk itself, i.e.:
+                // "5\r\nhello\r\n", and the body ends with an empty one, "0\r\n\r\n"
+                let mut chunk = vec![0; CHUNK_SIZE];
+                loop {
+                    let read = match reader.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(read) => read,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(err),
+                    };
+
+                    let mut framed = format!("{read:X}\r\n").into_bytes();
+                    framed.extend_from_slice(&chunk[..read]);
+                    framed.extend_from_slice(b"\r\n");
+                    stream.write_all(&framed)?;
+                }
+
+                // The body has been sent, so the trailers can be worked out now. They go
+                // after the empty chunk, laid out just like headers, i.e.:
+                // "0\r\nX-Checksum: 1a2b\r\n\r\n"
+                let mut last = String::from("0\r\n");
+                for (name, value) in self.trailers.map(|trailers| trailers()).unwrap_or_default() {
+                    let _ = write!(last, "{name}: {value}\r\n");
+                }
+                last.push_str("\r\n");
+                stream.write_all(last.as_bytes())
+            }
+        }
+    }
+
+    /// Writes the response for a client that doesn't understand chunked bodies (anything older
+    /// than HTTP/1.1), which is the same as write_to, except that a body of unknown length is
+    /// sent as it is, with nothing to say where it ends. The client knows it has all of it when
+    /// the connection closes, so the connection can't be used for anything else afterwards
+    pub fn write_unchunked_to<W: Write>(self, stream: &mut W) -> io::Result<()> {
+        let head = self.head(false);
+        match self.body {
+            Body::Chunked(mut reader) => {
+                stream.write_all(head.as_bytes())?;
+                io::copy(&mut reader, stream)?;
+                Ok(())
+            }
+            body => Response { body, ..self }.write_to(stream),
+        }
+    }
+
+    /// Writes everything but the body, which is what a response to a HEAD request looks like.
+    /// The headers are exactly the same as write_to would send, Content-Length included
+    pub fn write_head_to<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(self.head(self.version == HttpVersion::Http11).as_bytes())
+    }
+
+    // The status line and headers, up to and including the blank line after them. chunked is
+    // whether a body of unknown length is going to be sent in chunks
+    fn head(&self, chunked: bool) -> String {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+
+        // A 204 can't have a body, so it isn't allowed a Content-Length either. Neither is a
+        // 304, whose Content-Length would be for the body the client already has
+        if !matches!(self.status, 204 | 304) && self.header_value("Content-Length").is_none() {
+            match self.body_len() {
+                Some(len) => head.push_str(&format!("Content-Length: {len}\r\n")),
+                None if chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+
+        head.push_str("\r\n");
+        head
+    }
+}
+
+// Reads from an iterator of chunks, handing over at most one chunk per read, so a chunk that's
+// ready doesn't sit around waiting for the next one to fill up the rest of the buffer
+struct ChunkReader<I> {
+    chunks: I,
+    // The chunk currently being read, and how much of it has been read so far
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<I: Iterator<Item = Vec<u8>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // An empty chunk would look like the end of the body, so those are skipped over
+        while self.pos == self.chunk.len() {
+            match self.chunks.next() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+/// The attributes that go along with a cookie in its Set-Cookie header. The defaults leave them
+/// all out, which gives a cookie that lasts until the browser is closed, i.e.:
+/// ```text
+/// CookieOptions { path: Some("/".to_string()), http_only: true, ..CookieOptions::default() }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CookieOptions {
+    /// How many seconds the cookie lasts for. 0 tells the client to delete it straight away
+    pub max_age: Option<u64>,
+    /// Only send the cookie back for paths under this one
+    pub path: Option<String>,
+    /// Keep the cookie away from scripts running in the page
+    pub http_only: bool,
+    /// Only send the cookie back over HTTPS
+    pub secure: bool,
+    /// Whether the cookie gets sent with requests that come from other sites
+    pub same_site: Option<SameSite>,
+}
+
+/// Whether the cookie gets sent along with requests that come from other sites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    /// Never, so it only comes with requests from our own pages
+    Strict,
+    /// Only when following a link here from another site, and not for anything it loads
+    Lax,
+    /// Always, which browsers only allow for a cookie that's secure as well
+    None,
+}
+
+impl SameSite {
+    fn as_str(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Everything write_to sends for response, as text
+    fn written(response: Response) -> String {
+        let mut sent = Vec::new();
+        response.write_to(&mut sent).unwrap();
+        String::from_utf8(sent).unwrap()
+    }
+
+    // Undoes the chunked encoding on a body, checking each chunk's framing along the way
+    fn dechunk(mut body: &str) -> String {
+        let mut decoded = String::new();
+        loop {
+            let (size, rest) = body.split_once("\r\n").expect("a chunk size line");
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                assert_eq!(rest, "\r\n", "nothing should follow the last chunk");
+                return decoded;
+            }
+            decoded.push_str(&rest[..size]);
+            assert_eq!(&rest[size..size + 2], "\r\n");
+            body = &rest[size + 2..];
+        }
+    }
+
+    #[test]
+    fn round_trips_a_body_of_unknown_length_through_chunked_encoding() {
+        let text: String = (0..3000).map(|n| format!("{n} ")).collect();
+        let response = Response::ok().body_stream(io::Cursor::new(text.clone().into_bytes()));
+
+        let sent = written(response);
+        let (head, body) = sent.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"), "{head:?}");
+        assert!(!head.contains("Content-Length"), "{head:?}");
+        assert_eq!(dechunk(body), text);
+
+        let chunks = ["one", "", "three"].map(|chunk| chunk.as_bytes().to_vec());
+        let sent = written(Response::ok().body_chunks(chunks));
+        let (_, body) = sent.split_once("\r\n\r\n").unwrap();
+        assert_eq!(dechunk(body), "onethree");
+    }
+
+    #[test]
+    fn sends_the_trailers_after_the_last_chunk() {
+        let response = Response::ok()
+            .body_chunks([b"hello ".to_vec(), b"world".to_vec()])
+            .trailers(&["Content-MD5"], || {
+                // The MD5 of "hello world", in base64
+                vec![(
+                    "Content-MD5".to_string(),
+                    "XrY7u+Ae7tCTyyK7j1rNww==".to_string(),
+                )]
+            });
+
+        let sent = written(response);
+        assert!(sent.contains("\r\nTrailer: Content-MD5\r\n"), "{sent:?}");
+        assert!(
+            sent.ends_with("5\r\nworld\r\n0\r\nContent-MD5: XrY7u+Ae7tCTyyK7j1rNww==\r\n\r\n"),
+            "{sent:?}"
+        );
+    }
+
+    #[test]
+    fn sends_each_kind_of_redirect_to_its_location() {
+        let redirects = [
+            (Response::redirect("/new", true), 301, "MOVED PERMANENTLY"),
+            (Response::redirect("/new", false), 302, "FOUND"),
+            (
+                Response::redirect_preserve_method("/new", false),
+                307,
+                "TEMPORARY REDIRECT",
+            ),
+            (
+                Response::redirect_preserve_method("/new", true),
+                308,
+                "PERMANENT REDIRECT",
+            ),
+        ];
+
+        for (redirect, code, reason) in redirects {
+            let sent = written(redirect);
+            assert!(
+                sent.starts_with(&format!("HTTP/1.1 {code} {reason}\r\n")),
+                "{sent:?}"
+            );
+            assert!(sent.contains("\r\nLocation: /new\r\n"), "{sent:?}");
+            assert!(sent.contains("<a href=\"/new\">"), "{sent:?}");
+        }
+    }
+
+    #[test]
+    fn sends_each_cookie_in_a_set_cookie_line_of_its_own() {
+        let session = CookieOptions {
+            max_age: Some(3600),
+            path: Some("/".to_string()),
+            http_only: true,
+            secure: true,
+            same_site: Some(SameSite::Strict),
+        };
+        let response = Response::ok()
+            .set_cookie("session", "abc123", &session)
+            .set_cookie("theme", "dark", &CookieOptions::default());
+
+        let sent = written(response);
+        let cookies: Vec<&str> = sent
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .collect();
+        assert_eq!(
+            cookies,
+            [
+                "session=abc123; Max-Age=3600; Path=/; HttpOnly; Secure; SameSite=Strict",
+                "theme=dark",
+            ]
+        );
+    }
+}
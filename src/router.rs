@@ -0,0 +1,528 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    iter,
+};
+
+use crate::{
+    error_pages::ErrorPages,
+    middleware::{Middleware, Next},
+    request::{HttpMethod, Request},
+    response::Response,
+};
+
+/// What to do with a request for a path that only has a route with (or without) a "/" on the
+/// end, i.e.: a request for "/about/" when there's only a route for "/about"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// It doesn't match, and gets a 404
+    Off,
+    /// Redirect "/about/" to "/about"
+    Strip,
+    /// Redirect "/about" to "/about/"
+    Append,
+}
+
+// A handler takes the request that came in and decides what to send back. These get shared
+// between all of the pool's threads, so they have to be Send + Sync
+type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// Keeps track of which handler each request should go to, based on its method and path
+///
+/// A route's path can have parameters in it, which are segments starting with a ":", i.e.:
+/// "/users/:id", which matches "/users/42" (with the "id" parameter set to "42"), but not
+/// "/users" or "/users/42/posts". The last segment can also be a wildcard, which starts with
+/// a "*" and matches the whole rest of the path, slashes and all, i.e.: "/static/*path" matches
+/// "/static/css/app.css" (with "path" set to "css/app.css")
+///
+/// The more specific route always wins when more than one matches: a route without any
+/// parameters goes first, so "/users/me" can have a handler of its own, even with "/users/:id"
+/// around. Then come routes with parameters, and last of all the wildcards, longest first
+pub struct Router {
+    // The handlers for each route's path, by method, i.e.: "/about" => { HttpMethod::Get =>
+    // about }. Knowing every method a path has is what lets us tell a client that sent the
+    // wrong method (405) apart from one that asked for a path we don't have at all (404)
+    routes: HashMap<String, BTreeMap<HttpMethod, Handler>>,
+    // The paths in routes that have parameters, in the order they're tried in: the ones with
+    // just parameters in the order they were added, and then the wildcards, longest first
+    patterns: Vec<String>,
+    // Used for any request that doesn't match one of the routes
+    not_found: Handler,
+    // Used for any of those that not_found didn't have anything for either (see fallback)
+    fallback: Option<Handler>,
+    // Run around every request, in the order they were added, so the first one
+    // sees the request first and the response last
+    middlewares: Vec<Box<dyn Middleware>>,
+    // Paths whose requests go straight to their handlers, without running any middlewares
+    unwrapped: HashSet<String>,
+    // Paths whose handlers read the request's body themselves, as it comes in
+    streaming: HashSet<String>,
+    trailing_slash: TrailingSlash,
+    // Fills in the body of any error response that comes out of the middlewares
+    error_pages: ErrorPages,
+}
+
+impl Router {
+    /// Creates a Router with no routes, where every request goes to not_found
+    pub fn new<F>(not_found: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        Router {
+            routes: HashMap::new(),
+            patterns: Vec::new(),
+            not_found: Box::new(not_found),
+            fallback: None,
+            middlewares: Vec::new(),
+            unwrapped: HashSet::new(),
+            streaming: HashSet::new(),
+            trailing_slash: TrailingSlash::Off,
+            error_pages: ErrorPages::new(),
+        }
+    }
+
+    /// Sends requests with the given method and path to handler. Registering the same
+    /// method and path again replaces the handler that was there before
+    pub fn route<F>(&mut self, method: HttpMethod, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        if is_pattern(path) && !self.routes.contains_key(path) {
+            self.patterns.push(path.to_string());
+            self.patterns
+                .sort_by_key(|pattern| match pattern.rsplit('/').next() {
+                    Some(last) if last.starts_with('*') => Reverse(pattern.split('/').count()),
+                    _ => Reverse(usize::MAX),
+                });
+        }
+
+        self.routes
+            .entry(path.to_string())
+            .or_default()
+            .insert(method, Box::new(handler));
+    }
+
+    /// Sends GET requests for path to handler. HEAD requests for path go to it too,
+    /// unless they have a route of their own
+    pub fn get<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Get, path, handler);
+    }
+
+    /// Same as get, except that requests for path skip the middlewares, i.e.: for a health
+    /// check, which has to answer no matter what, and shouldn't fill up the logs either
+    pub fn get_unwrapped<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.get(path, handler);
+        self.unwrapped.insert(path.to_string());
+    }
+
+    /// Sends POST requests for path to handler, without reading their bodies first. Instead,
+    /// the handler reads the body from request.body_reader(), as it comes in, so it can be
+    /// bigger than the limits would let us hold in memory all at once, i.e.: for an upload
+    /// that goes straight out to a file
+    pub fn post_streaming<F>(&mut self, path: &str, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(HttpMethod::Post, path, handler);
+        self.streaming.insert(path.to_string());
+    }
+
+    /// Sends any request that doesn't match one of the routes, and that not_found answers with
+    /// a 404, to handler instead, i.e.: to serve a single-page app's index.html for whatever
+    /// path the app does its own routing for, while the files it's made of are still served
+    /// by not_found. Setting it again replaces the one that was there before
+    pub fn fallback<F>(&mut self, handler: F)
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    /// Whether the route that path goes to reads the request's body itself, in which case
+    /// it's up to the handler to read it, instead of it being read before the Router has it
+    pub fn streams_body(&self, path: &str) -> bool {
+        self.find(path)
+            .is_some_and(|(route, _)| self.streaming.contains(route))
+    }
+
+    /// Whether there's a handler for requests with this method and path, as opposed to them
+    /// getting a 404 or a 405. With a fallback, any path without a route of its own has one
+    pub fn handles(&self, method: HttpMethod, path: &str) -> bool {
+        match self.find(path) {
+            Some((route, _)) => {
+                let methods = &self.routes[route];
+                methods.contains_key(&method)
+                    || (method == HttpMethod::Head && methods.contains_key(&HttpMethod::Get))
+            }
+            None => self.fallback.is_some(),
+        }
+    }
+
+    /// Adds a middleware to the end of the chain, so it runs after the ones that were
+    /// added before it, and before the handler
+    pub fn wrap(&mut self, middleware: impl Middleware + 'static) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    /// Sets whether a request for a path that would have a route, if only it had (or didn't
+    /// have) a "/" on the end, gets redirected to it. Either way, it's only requests with the
+    /// "/" on the wrong end that get redirected, so both can still have routes of their own
+    pub fn set_trailing_slash(&mut self, trailing_slash: TrailingSlash) {
+        self.trailing_slash = trailing_slash;
+    }
+
+    /// Replaces the pages that error responses get filled in with
+    pub fn set_error_pages(&mut self, error_pages: ErrorPages) {
+        self.error_pages = error_pages;
+    }
+
+    /// The pages that error responses get filled in with, for responding to
+    /// requests that never made it as far as the Router
+    pub fn error_pages(&self) -> &ErrorPages {
+        &self.error_pages
+    }
+
+    /// Finds the handler for the request and runs it (through the middlewares). A path we
+    /// don't have goes to not_found (and then the fallback, if there is one, when not_found
+    /// answers with a 404), and a path we have, but not for the request's method, gets a 405.
+    /// If the response is an error, it gets its error page
+    ///
+    /// HEAD and OPTIONS requests get answered for us, unless a route has a handler of its own
+    /// for them: HEAD goes to the GET handler, and OPTIONS gets told which methods the path
+    /// (or, for "OPTIONS *", the server as a whole) can take
+    ///
+    /// If the route has parameters, they're filled in on the request before any of the
+    /// middlewares see it
+    pub fn handle(&self, request: &mut Request) -> Response {
+        let route = self.find(&request.path);
+        let middlewares: &[Box<dyn Middleware>] = match &route {
+            Some((path, _)) if self.unwrapped.contains(*path) => &[],
+            _ => &self.middlewares,
+        };
+        let methods = route.map(|(path, params)| {
+            request.params = params;
+            &self.routes[path]
+        });
+        let request: &Request = request;
+
+        let response = match methods {
+            Some(methods) => {
+                let mut handler = methods.get(&request.method);
+
+                // A HEAD request is a GET request without the body, so we can answer it with
+                // the GET handler, and leave it to whoever writes the response to drop the body
+                if handler.is_none() && request.method == HttpMethod::Head {
+                    handler = methods.get(&HttpMethod::Get);
+                }
+
+                match handler {
+                    Some(handler) => Next::new(middlewares, handler.as_ref()).run(request),
+                    None => {
+                        let allow = allowed_methods(methods.keys().copied());
+                        let unhandled = |request: &Request| {
+                            if request.method == HttpMethod::Options {
+                                options(&allow)
+                            } else {
+                                Response::ok()
+                                    .status(405, "METHOD NOT ALLOWED")
+                                    .header("Allow", &allow)
+                            }
+                        };
+                        Next::new(middlewares, &unhandled).run(request)
+                    }
+                }
+            }
+            // "OPTIONS *" is about the server as a whole, rather than any one route, so it gets
+            // every method there's a handler for anywhere, in a "200 OK" with an empty body
+            // (so "Content-Length: 0"), instead of the 204 a route's OPTIONS gets
+            None if request.method == HttpMethod::Options && request.path == "*" => {
+                let allow = allowed_methods(self.routes.values().flat_map(BTreeMap::keys).copied());
+                let server_options = |_: &Request| options(&allow).status(200, "OK");
+                Next::new(middlewares, &server_options).run(request)
+            }
+            None => match self.trailing_slash_redirect(request) {
+                Some(location) => {
+                    let redirect = |_: &Request| Response::redirect(&location, true);
+                    Next::new(middlewares, &redirect).run(request)
+                }
+                None => {
+                    let unmatched = |request: &Request| {
+                        let response = (self.not_found)(request);
+                        match &self.fallback {
+                            Some(fallback) if response.status_code() == 404 => fallback(request),
+                            _ => response,
+                        }
+                    };
+                    Next::new(middlewares, &unmatched).run(request)
+                }
+            },
+        };
+
+        self.error_pages.render(response)
+    }
+
+    // Where to redirect a request that doesn't have a route to, if it would with the "/" on
+    // its end added or taken away, keeping its query string, i.e.: "/about?lang=en" for
+    // "/about/?lang=en". Only GET and HEAD requests get redirected, since browsers change any
+    // other method to GET when they follow a 301
+    fn trailing_slash_redirect(&self, request: &Request) -> Option<String> {
+        if !matches!(request.method, HttpMethod::Get | HttpMethod::Head) {
+            return None;
+        }
+
+        let path = &request.path;
+        let location = match self.trailing_slash {
+            TrailingSlash::Off => return None,
+            TrailingSlash::Strip if path.len() > 1 => path.strip_suffix('/')?.to_string(),
+            TrailingSlash::Strip => return None,
+            TrailingSlash::Append if path.ends_with('/') => return None,
+            TrailingSlash::Append => format!("{path}/"),
+        };
+
+        // A Location starting with "//" is another server's address, i.e.: "//example.com"
+        // for "//example.com/", which a wildcard route could otherwise send clients off to
+        if location.starts_with("//") || self.find(&location).is_none() {
+            return None;
+        }
+
+        if request.query_string.is_empty() {
+            Some(location)
+        } else {
+            Some(format!("{location}?{}", request.query_string))
+        }
+    }
+
+    // The route that path goes to, along with the values of its parameters (if it has any).
+    // A route without parameters has to match the path exactly, so it's just looked up, and
+    // only then do we go through the ones that have them
+    fn find(&self, path: &str) -> Option<(&str, HashMap<String, String>)> {
+        if !is_pattern(path) {
+            if let Some((route, _)) = self.routes.get_key_value(path) {
+                return Some((route, HashMap::new()));
+            }
+        }
+
+        self.patterns.iter().find_map(|pattern| {
+            match_pattern(pattern, path).map(|params| (pattern.as_str(), params))
+        })
+    }
+}
+
+// Whether a route's path has any parameters (or a wildcard) in it, i.e.: "/users/:id"
+fn is_pattern(path: &str) -> bool {
+    path.split('/')
+        .any(|segment| segment.starts_with(':') || segment.starts_with('*'))
+}
+
+// Matches path against pattern a segment at a time, giving back the parameters if every
+// segment matches, i.e.: { "id" => "42" } for "/users/42" against "/users/:id". A parameter
+// matches any one segment, as long as it isn't empty, and is left just as it is in the path
+// (still percent-encoded), the same as the rest of the path. A wildcard matches whatever's
+// left, even if that's nothing, i.e.: { "path" => "" } for "/static/" against "/static/*path"
+// (but "/static" doesn't match at all)
+fn match_pattern(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut segments = path.split('/');
+
+    for expected in pattern.split('/') {
+        if let Some(name) = expected.strip_prefix('*') {
+            let rest = iter::once(segments.next()?)
+                .chain(segments)
+                .collect::<Vec<_>>()
+                .join("/");
+            params.insert(name.to_string(), rest);
+            return Some(params);
+        }
+
+        let segment = segments.next()?;
+        match expected.strip_prefix(':') {
+            Some(name) if !segment.is_empty() => {
+                params.insert(name.to_string(), segment.to_string());
+            }
+            None if segment == expected => {}
+            _ => return None,
+        }
+    }
+
+    // The path can't have any segments left over, either
+    match segments.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+// The answer to an OPTIONS request that doesn't have a handler of its own, which is just
+// the methods that can be used, with no body
+fn options(allow: &str) -> Response {
+    Response::ok()
+        .status(204, "NO CONTENT")
+        .header("Allow", allow)
+}
+
+// The value of the Allow header for the methods that have handlers, i.e.: "GET, HEAD, OPTIONS,
+// POST". HEAD works anywhere GET does, and OPTIONS works everywhere, even without handlers
+fn allowed_methods(methods: impl Iterator<Item = HttpMethod>) -> String {
+    let mut allowed: BTreeSet<HttpMethod> = methods.collect();
+    if allowed.contains(&HttpMethod::Get) {
+        allowed.insert(HttpMethod::Head);
+    }
+    allowed.insert(HttpMethod::Options);
+
+    allowed
+        .iter()
+        .map(HttpMethod::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::request::{parse_request, RequestLimits};
+
+    // A Router with a page at "/about" that can be fetched or posted to
+    fn router() -> Router {
+        let mut router = Router::new(|_| Response::ok().status(404, "NOT FOUND"));
+        router.get("/about", |_| Response::ok().body("about"));
+        router.route(HttpMethod::Post, "/about", |_| Response::ok());
+        router
+    }
+
+    // What router answers with for the request in raw
+    fn handle(router: &Router, raw: &str) -> Response {
+        let mut reader = Cursor::new(raw.as_bytes());
+        let mut request = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        router.handle(&mut request)
+    }
+
+    #[test]
+    fn answers_options_for_a_path_with_the_methods_it_has() {
+        let response = handle(&router(), "OPTIONS /about HTTP/1.1\r\n\r\n");
+
+        assert_eq!(response.status_code(), 204);
+        assert_eq!(
+            response.header_value("Allow"),
+            Some("GET, POST, HEAD, OPTIONS")
+        );
+        assert_eq!(response.contents(), Some(&[][..]));
+    }
+
+    #[test]
+    fn leaves_options_to_a_route_that_has_a_handler_for_it() {
+        let mut router = router();
+        router.route(HttpMethod::Options, "/about", |_| {
+            Response::ok().header("Allow", "GET")
+        });
+
+        let response = handle(&router, "OPTIONS /about HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.header_value("Allow"), Some("GET"));
+    }
+
+    #[test]
+    fn answers_options_star_with_every_method_the_server_has() {
+        let mut router = router();
+        router.route(HttpMethod::Delete, "/users/:id", |_| Response::ok());
+
+        let response = handle(&router, "OPTIONS * HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(
+            response.header_value("Allow"),
+            Some("GET, POST, DELETE, HEAD, OPTIONS")
+        );
+
+        let mut sent = Vec::new();
+        response.write_to(&mut sent).unwrap();
+        let sent = String::from_utf8(sent).unwrap();
+        let (head, body) = sent.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nContent-Length: 0"), "{head:?}");
+        assert_eq!(body, "");
+    }
+
+    // Answers with the value of the id parameter, so the tests can see what it was set to
+    fn user(request: &Request) -> Response {
+        Response::ok().body(format!("user {}", request.param("id").unwrap()))
+    }
+
+    #[test]
+    fn fills_in_the_parameters_of_the_route_a_path_matches() {
+        let mut router = router();
+        router.get("/users/:id", user);
+        router.get("/users/me", |_| Response::ok().body("me"));
+
+        let response = handle(&router, "GET /users/42 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.contents(), Some(&b"user 42"[..]));
+
+        // A route without parameters goes before one with them
+        let response = handle(&router, "GET /users/me HTTP/1.1\r\n\r\n");
+        assert_eq!(response.contents(), Some(&b"me"[..]));
+
+        // A parameter is one whole segment, no more and no less
+        for unmatched in ["/users", "/users/", "/users/42/posts"] {
+            let response = handle(&router, &format!("GET {unmatched} HTTP/1.1\r\n\r\n"));
+            assert_eq!(response.status_code(), 404, "{unmatched}");
+        }
+    }
+
+    #[test]
+    fn gives_a_wildcard_the_rest_of_the_path() {
+        let mut router = router();
+        router.get("/static/*path", |request| {
+            Response::ok().body(request.param("path").unwrap().to_string())
+        });
+
+        let response = handle(&router, "GET /static/css/app.css HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code(), 200);
+        assert_eq!(response.contents(), Some(&b"css/app.css"[..]));
+
+        // A more specific route gets the paths it matches, and the wildcard keeps the rest
+        router.get("/static/css/:file", |_| Response::ok().body("stylesheet"));
+        let response = handle(&router, "GET /static/css/app.css HTTP/1.1\r\n\r\n");
+        assert_eq!(response.contents(), Some(&b"stylesheet"[..]));
+        let response = handle(&router, "GET /static/css/fonts/a.woff HTTP/1.1\r\n\r\n");
+        assert_eq!(response.contents(), Some(&b"css/fonts/a.woff"[..]));
+    }
+
+    #[test]
+    fn redirects_to_the_path_with_or_without_its_trailing_slash() {
+        let mut router = router();
+        router.get("/docs/", |_| Response::ok());
+        let redirect = |router: &Router, path: &str| {
+            let response = handle(router, &format!("GET {path} HTTP/1.1\r\n\r\n"));
+            let location = response.header_value("Location").map(str::to_string);
+            (response.status_code(), location)
+        };
+
+        // Without a policy, the path has to match as it is
+        assert_eq!(redirect(&router, "/about/"), (404, None));
+
+        router.set_trailing_slash(TrailingSlash::Strip);
+        assert_eq!(redirect(&router, "/about/"), (301, Some("/about".into())));
+        assert_eq!(
+            redirect(&router, "/about/?lang=en&page=2"),
+            (301, Some("/about?lang=en&page=2".into()))
+        );
+        assert_eq!(redirect(&router, "/docs"), (404, None));
+
+        router.set_trailing_slash(TrailingSlash::Append);
+        assert_eq!(redirect(&router, "/docs"), (301, Some("/docs/".into())));
+        assert_eq!(
+            redirect(&router, "/docs?q=slash"),
+            (301, Some("/docs/?q=slash".into()))
+        );
+        assert_eq!(redirect(&router, "/about/"), (404, None));
+
+        // Browsers turn a redirected POST into a GET, so that doesn't get redirected at all
+        let response = handle(&router, "POST /docs HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(response.status_code(), 404);
+    }
+}
@@ -0,0 +1,1394 @@
+use std::{
+    cell::{Cell, RefCell},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+use crate::compression;
+#[cfg(feature = "json")]
+use crate::handlers::status;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{
+    autoscale::Autoscaler,
+    config::{ConfigError, Listen, ServerConfig},
+    error::{ServerError, StartError},
+    error_pages::ErrorPages,
+    handlers::{countdown, fallback_file, hello, sleep, static_file, stream, upload},
+    health::ServerState,
+    kv::KeyValueStore,
+    listener::{Connection, Listener, ReadTimeout},
+    logger::{escape, LogOutput, RequestLogger},
+    metrics::Metrics,
+    middleware::{BasicAuth, TokenAuth},
+    rate_limit::RateLimiter,
+    request::{parse_request, HttpMethod, HttpVersion, ParseError, Request},
+    response::{Response, Transferred},
+    router::Router,
+    session::SessionStore,
+    set_job_context, Spawner, ThreadPool,
+};
+
+// How long the accept loop waits for a connection to come in, before it checks whether it's
+// been told to shut down (or reload, and so on). A connection that comes in while it's
+// waiting gets accepted straight away
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// A handler for one of the routes a Server was given
+type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+// Where a reload gets the new config from (see reload_with)
+type ConfigSource = Box<dyn Fn() -> Result<ServerConfig, ConfigError> + Send>;
+
+/// The web server: everything that accepts connections, reads the requests off of them,
+/// and sends back the responses our Router (along with any routes it's given) comes up with
+///
+/// `run` serves until Ctrl-C is pressed, `run_with_shutdown` until a flag of the caller's
+/// gets set, and `start` serves on a thread of its own, handing back a ServerHandle to stop
+/// it with
+///
+/// Every route's handler gets the server's state along with each request, which is where
+/// anything they share goes (i.e.: a database pool, or a cache), instead of in a global.
+/// Requests are handled on every Worker at once, so the state has to be safe to share between
+/// threads, i.e.: behind a Mutex, or made out of atomics. A server that's made with `new`
+/// doesn't have any, and its handlers get a `()`
+pub struct Server<S = ()> {
+    config: ServerConfig,
+    state: Arc<S>,
+    routes: Vec<(HttpMethod, String, Handler)>,
+    fallback: Option<Handler>,
+    config_source: Option<ConfigSource>,
+}
+
+impl Server {
+    /// A server that serves with config, once it's started, without any state
+    pub fn new(config: ServerConfig) -> Server {
+        Server::with_state(config, ())
+    }
+}
+
+impl<S: Send + Sync + 'static> Server<S> {
+    /// A server that serves with config, once it's started, and hands state to its handlers
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    ///     sync::atomic::{AtomicUsize, Ordering},
+    /// };
+    ///
+    /// use web_server_rust::{
+    ///     config::{Listen, ServerConfig}, request::HttpMethod, response::Response, Server,
+    /// };
+    ///
+    /// // Counts every request to /count, whichever Worker it ends up on
+    /// #[derive(Default)]
+    /// struct Counter {
+    ///     requests: AtomicUsize,
+    /// }
+    ///
+    /// let config = ServerConfig { port: 0, ..ServerConfig::default() };
+    /// let server = Server::with_state(config, Counter::default())
+    ///     .route(HttpMethod::Get, "/count", |_, counter: &Counter| {
+    ///         let count = counter.requests.fetch_add(1, Ordering::SeqCst) + 1;
+    ///         Response::ok().body(count.to_string())
+    ///     })
+    ///     .start()
+    ///     .unwrap();
+    ///
+    /// let Listen::Tcp(address) = &server.addresses()[0] else { unreachable!() };
+    /// let mut stream = TcpStream::connect(address).unwrap();
+    /// stream.write_all(b"GET /count HTTP/1.1\r\n\r\n").unwrap();
+    /// stream.write_all(b"GET /count HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    /// let mut responses = String::new();
+    /// stream.read_to_string(&mut responses).unwrap();
+    /// assert!(responses.ends_with("\r\n\r\n2"));
+    ///
+    /// server.shutdown();
+    /// ```
+    pub fn with_state(config: ServerConfig, state: S) -> Server<S> {
+        Server {
+            config,
+            state: Arc::new(state),
+            routes: Vec::new(),
+            fallback: None,
+            config_source: None,
+        }
+    }
+
+    /// Has a reload (from a SIGHUP, or the ServerHandle's `reload_flag`) get the new config
+    /// from source, i.e.: `ServerConfig::from_env_and_args` for a config that came from the
+    /// environment and the program's arguments. Without one, there's nothing to reload from,
+    /// so a reload is turned down, and the server carries on with the config it was given
+    pub fn reload_with<F>(mut self, source: F) -> Server<S>
+    where
+        F: Fn() -> Result<ServerConfig, ConfigError> + Send + 'static,
+    {
+        self.config_source = Some(Box::new(source));
+        self
+    }
+
+    /// Sends requests with this method and path to handler, on top of the server's own
+    /// routes, along with the server's state. The path can have parameters in it, the same
+    /// as for the Router, i.e.: "/users/:id". A route given here takes the place of one of
+    /// the server's own with the same method and path
+    pub fn route<F>(mut self, method: HttpMethod, path: &str, handler: F) -> Server<S>
+    where
+        F: Fn(&Request, &S) -> Response + Send + Sync + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        let handler: Handler = Arc::new(move |request| handler(request, &state));
+        self.routes.push((method, path.to_string(), handler));
+        self
+    }
+
+    /// Sends any request that doesn't match a route, or a file under the static root, to
+    /// handler, along with the server's state, instead of it getting a 404, i.e.: to serve a
+    /// single-page app's index.html for the paths the app does its own routing for. This
+    /// takes the place of the file the config's `fallback` would have served
+    ///
+    /// ```
+    /// use std::{
+    ///     io::{Read, Write},
+    ///     net::TcpStream,
+    /// };
+    ///
+    /// use web_server_rust::{
+    ///     config::{Listen, ServerConfig}, request::Request, response::Response, Server,
+    /// };
+    ///
+    /// let config = ServerConfig { port: 0, ..ServerConfig::default() };
+    /// let server = Server::new(config)
+    ///     .fallback(|request: &Request, _| {
+    ///         let app = format!("<div id=\"app\" data-path=\"{}\"></div>", request.path);
+    ///         Response::ok().body(app)
+    ///     })
+    ///     .start()
+    ///     .unwrap();
+    ///
+    /// let Listen::Tcp(address) = &server.addresses()[0] else { unreachable!() };
+    /// let mut stream = TcpStream::connect(address).unwrap();
+    /// stream.write_all(b"GET /settings/profile HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    /// assert!(response.starts_with("HTTP/1.1 200 OK"));
+    /// assert!(response.ends_with("data-path=\"/settings/profile\"></div>"));
+    ///
+    /// server.shutdown();
+    /// ```
+    pub fn fallback<F>(mut self, handler: F) -> Server<S>
+    where
+        F: Fn(&Request, &S) -> Response + Send + Sync + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        self.fallback = Some(Arc::new(move |request| handler(request, &state)));
+        self
+    }
+
+    /// Serves until Ctrl-C is pressed, and then shuts down, letting the requests in progress
+    /// finish first. A SIGHUP reloads the config (see `reload_with`), and a SIGUSR1 reopens
+    /// the access log
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server couldn't be started, or if the signal handlers
+    /// couldn't be set (i.e.: because another server in this process already set them)
+    pub fn run(self) -> Result<(), StartError> {
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&shutting_down);
+        ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+            .map_err(|err| StartError::Io(io::Error::other(err)))?;
+
+        // A SIGHUP (i.e.: from "kill -HUP <pid>") reloads the config, so things like the
+        // static root can change without dropping any connections
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested))
+            .map_err(StartError::Io)?;
+
+        // A SIGUSR1 reopens the access log, which is what logrotate's postrotate script sends,
+        // i.e.: "kill -USR1 $(cat /run/web-server.pid)"
+        let reopen_requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&reopen_requested))
+            .map_err(StartError::Io)?;
+
+        self.start_with(shutting_down, reload_requested, reopen_requested)?
+            .wait();
+        Ok(())
+    }
+
+    /// Serves until shutdown gets set, and then shuts down, the same way as `run` does
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server couldn't be started
+    pub fn run_with_shutdown(self, shutdown: Arc<AtomicBool>) -> Result<(), StartError> {
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        let reopen_requested = Arc::new(AtomicBool::new(false));
+        self.start_with(shutdown, reload_requested, reopen_requested)?
+            .wait();
+        Ok(())
+    }
+
+    /// Starts serving on a thread of its own, and hands back a ServerHandle for finding out
+    /// where it's listening, and stopping it. Everything that could stop the server from
+    /// starting (i.e.: a port that's already in use) has been checked by the time this
+    /// returns, so the server is already listening
+    ///
+    /// ```
+    /// use std::{io::{Read, Write}, net::TcpStream};
+    ///
+    /// use web_server_rust::{
+    ///     config::{Listen, ServerConfig}, request::HttpMethod, response::Response, Server,
+    /// };
+    ///
+    /// // Port 0 lets the OS pick a port that's free
+    /// let config = ServerConfig { port: 0, ..ServerConfig::default() };
+    /// let server = Server::new(config)
+    ///     .route(HttpMethod::Get, "/ping", |_, _| Response::ok().body("pong"))
+    ///     .start()
+    ///     .unwrap();
+    ///
+    /// let Listen::Tcp(address) = &server.addresses()[0] else { unreachable!() };
+    /// let mut stream = TcpStream::connect(address).unwrap();
+    /// stream.write_all(b"GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+    /// let mut response = String::new();
+    /// stream.read_to_string(&mut response).unwrap();
+    /// assert!(response.starts_with("HTTP/1.1 200 OK"));
+    /// assert!(response.ends_with("pong"));
+    ///
+    /// server.shutdown();
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server couldn't listen on one of its addresses, or if its
+    /// options don't work together (i.e.: --tls-cert without --tls-key)
+    pub fn start(self) -> Result<ServerHandle, StartError> {
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        let reopen_requested = Arc::new(AtomicBool::new(false));
+        self.start_with(shutting_down, reload_requested, reopen_requested)
+    }
+
+    fn start_with(
+        self,
+        shutting_down: Arc<AtomicBool>,
+        reload_requested: Arc<AtomicBool>,
+        reopen_requested: Arc<AtomicBool>,
+    ) -> Result<ServerHandle, StartError> {
+        let Server {
+            config,
+            routes,
+            fallback,
+            config_source,
+            ..
+        } = self;
+
+        // Listen for any connections coming into our program by using a Listener
+        // "bound" to each IP address/port (or Unix socket) we've been given
+        let mut listeners = Vec::new();
+        for address in config.bind_addresses() {
+            match Listener::bind(&address, config.listen_backlog) {
+                Ok(listener) => listeners.push(listener),
+                Err(err) => return Err(StartError::Bind(address, err)),
+            }
+        }
+
+        // When the port is 0, the OS picks one for us, and this is how we find out which
+        let addresses = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(StartError::Io)?;
+
+        // If we've been given a certificate and key, every connection gets wrapped in TLS
+        #[cfg(feature = "tls")]
+        let tls = load_tls(&config)?;
+        #[cfg(not(feature = "tls"))]
+        if config.tls_cert.is_some() || config.tls_key.is_some() {
+            return Err(StartError::Config(
+                "This server was built without the \"tls\" feature, so it can't serve HTTPS"
+                    .to_string(),
+            ));
+        }
+
+        // Every request gets logged to stdout, unless there's a file for it
+        let access_log = match &config.access_log {
+            Some(path) => {
+                LogOutput::file(path).map_err(|err| StartError::AccessLog(path.clone(), err))?
+            }
+            None => LogOutput::new(io::stdout()),
+        };
+        let access_log = Arc::new(access_log);
+
+        // Create a ThreadPool with a set number of threads so we can handle requests
+        // coming into our server in a multi-threaded/concurrent way
+        let pool = ThreadPool::build(config.workers).map_err(StartError::Pool)?;
+        println!(
+            "Listening on {} with {} workers",
+            addresses
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            config.workers
+        );
+
+        // Everything the Router uses that has to stay the same when the config gets reloaded:
+        // whether we're ready, the metrics, how many connections there are, everyone's
+        // sessions, and where the log goes
+        let state = Arc::new(ServerState::new());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let parts = RouterParts {
+            spawner: pool.spawner(),
+            state: Arc::clone(&state),
+            metrics: Arc::new(Metrics::new(pool.monitor())),
+            connections: Arc::clone(&connections),
+            sessions: Arc::new(SessionStore::new(
+                config.session_ttl,
+                config.tls_cert.is_some(),
+            )),
+            kv: Arc::new(KeyValueStore::new()),
+            access_log: Arc::clone(&access_log),
+            routes,
+            fallback,
+        };
+
+        // Each connection gets the config, for its timeout and limits, along with the Router
+        // that was built from it. They're both swapped out at once when the config gets reloaded
+        let site = Arc::new(Site::new(config, &parts));
+
+        // Waiting for a connection with listener.incoming() would block forever if nobody ever
+        // connects again, so we'd never notice the flag (or the connections coming in on our other
+        // listeners). Instead, the listeners are non-blocking, and when there's nobody waiting to
+        // connect on any of them, we take a short nap and check again
+        for listener in &listeners {
+            listener.set_nonblocking(true).map_err(StartError::Io)?;
+        }
+
+        // Everything's been set up now, so we're ready for real requests, as long as there are
+        // any static files for us to serve
+        check_ready(&state, &site.config);
+
+        let accept_loop = AcceptLoop {
+            listeners,
+            pool,
+            parts,
+            state: Arc::clone(&state),
+            connections,
+            #[cfg(feature = "tls")]
+            tls,
+            shutting_down: Arc::clone(&shutting_down),
+            reload_requested: Arc::clone(&reload_requested),
+            reopen_requested,
+            config_source,
+        };
+        let thread = thread::Builder::new()
+            .name("accept".to_string())
+            .spawn(move || accept_loop.run(site))
+            .map_err(StartError::Io)?;
+
+        Ok(ServerHandle {
+            addresses,
+            shutting_down,
+            reload_requested,
+            access_log,
+            state,
+            thread,
+        })
+    }
+}
+
+/// A server that's running on a thread of its own, from `Server::start`
+pub struct ServerHandle {
+    // Where it's listening, with the port the OS picked for any that asked for port 0
+    addresses: Vec<Listen>,
+    shutting_down: Arc<AtomicBool>,
+    reload_requested: Arc<AtomicBool>,
+    access_log: Arc<LogOutput>,
+    state: Arc<ServerState>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// Where the server is listening, with the port the OS picked for any address
+    /// that asked for port 0
+    pub fn addresses(&self) -> &[Listen] {
+        &self.addresses
+    }
+
+    /// Setting this flag shuts the server down, the same as calling `shutdown`, but it can
+    /// be done from anywhere (i.e.: a signal handler)
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutting_down)
+    }
+
+    /// Setting this flag has the server load its config again, from wherever it was told to
+    /// with `reload_with`. A server that wasn't told where turns the reload down
+    pub fn reload_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.reload_requested)
+    }
+
+    /// Opens the access log's file again, so that once it's been moved out of the way (i.e.:
+    /// by logrotate), the log carries on in a new file where it used to be. Every line
+    /// goes whole to one file or the other, even with requests being logged as it happens
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file couldn't be opened, in which case the log carries on
+    /// in the old one. Logging to stdout, there's nothing to reopen, and it always succeeds
+    pub fn reopen_logs(&self) -> io::Result<()> {
+        self.access_log.reopen()
+    }
+
+    /// Starts draining the server, i.e.: before it's taken out of a load balancer. It stops
+    /// listening, so anyone new trying to connect gets turned away, and /readyz says it isn't
+    /// ready. The connections it's already got have their requests finished, with a
+    /// "Connection: close" on each response, so none of them stay open for another one.
+    /// It keeps running until it's shut down, which waits for whatever's left
+    pub fn drain(&self) {
+        self.state.start_draining();
+    }
+
+    /// Stops taking new connections, and waits for the ones in progress to finish (for up
+    /// to the shutdown timeout), so nothing the server started is left running afterwards
+    pub fn shutdown(self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.wait();
+    }
+
+    /// Waits for the server to shut down, once something has set its shutdown flag
+    pub fn wait(self) {
+        if self.thread.join().is_err() {
+            eprintln!("The server's accept loop panicked");
+        }
+    }
+}
+
+// Takes each connection that comes in on the listeners, and hands it over to the pool, until
+// it's told to shut down. It runs on a thread of its own, which Server::start starts
+struct AcceptLoop {
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    parts: RouterParts,
+    state: Arc<ServerState>,
+    // How many connections are being handled (or waiting to be) right now
+    connections: Arc<AtomicUsize>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    shutting_down: Arc<AtomicBool>,
+    reload_requested: Arc<AtomicBool>,
+    reopen_requested: Arc<AtomicBool>,
+    config_source: Option<ConfigSource>,
+}
+
+impl AcceptLoop {
+    fn run(mut self, mut site: Arc<Site>) {
+        // Whether we've stopped accepting connections, since there are already too many waiting
+        // on the pool. Anyone connecting in the meantime waits in the OS's backlog, which is
+        // also how a load balancer in front of us can tell we're busy
+        let mut parked = false;
+        let mut autoscaler = Autoscaler::new();
+
+        // Loop over the "incoming" stream data from the listeners above, until we're told to stop
+        // Each call gives us a "possible" connection, so we have to keep looping
+        // until we successfully receive the connection
+        while !self.shutting_down.load(Ordering::SeqCst) {
+            // Closing the listeners means anyone trying to connect gets turned away straight
+            // away, instead of waiting in the backlog for a connection we'll never accept
+            if self.state.is_draining() && !self.listeners.is_empty() {
+                println!("Draining the server; finishing current requests, and not accepting more");
+                self.listeners.clear();
+            }
+
+            let queued = self.pool.queued_count();
+            match site.config.queue_water_marks() {
+                Some((high, _)) if !parked && queued >= high => {
+                    parked = true;
+                    eprintln!("{queued} connections are waiting on the pool; not accepting more");
+                }
+                Some((_, low)) if parked && queued <= low => {
+                    parked = false;
+                    eprintln!("Down to {queued} connections waiting on the pool; accepting again");
+                }
+                Some(_) => {}
+                None => parked = false,
+            }
+
+            // The policy comes from the Site, so a reload can turn it on, off, or change it
+            if let Some(policy) = site.config.autoscale_policy() {
+                autoscaler.tick(&mut self.pool, &policy);
+            }
+
+            let mut accepted = false;
+            if !parked {
+                for listener in &self.listeners {
+                    match listener.accept() {
+                        Ok(stream) => {
+                            accepted = true;
+                            self.accept(stream, &site);
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                        Err(err) => eprintln!("Unable to accept connection: {err}"),
+                    }
+                }
+            }
+
+            // Connections that are already open carry on with the Site they've already got, and
+            // only the ones that come in from here on get the new one
+            if self.reload_requested.swap(false, Ordering::SeqCst) {
+                let reloaded = reload(self.config_source.as_ref(), &site.config, &self.parts);
+                if let Some(reloaded) = reloaded {
+                    check_ready(&self.state, &reloaded.config);
+                    site = Arc::new(reloaded);
+                }
+            }
+            if self.reopen_requested.swap(false, Ordering::SeqCst) {
+                match self.parts.access_log.reopen() {
+                    Ok(()) => println!("Reopened the access log"),
+                    Err(err) => eprintln!("Unable to reopen the access log: {err}"),
+                }
+            }
+
+            // Only wait when nobody was waiting, so a busy server doesn't slow itself down. A
+            // parked loop just naps, since the connections it isn't taking would wake it up
+            // straight away otherwise
+            if parked {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            } else if !accepted {
+                if let Err(err) =
+                    Listener::wait_for_connection(&self.listeners, ACCEPT_POLL_INTERVAL)
+                {
+                    eprintln!("Unable to wait for connections: {err}");
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+            }
+        }
+
+        // Close the listeners, so anyone trying to connect from here on gets turned away straight
+        // away, then let the requests that are already in progress finish before we exit. A
+        // request that's taking too long (i.e.: a handler stuck in a loop) is left behind, so
+        // it can't keep the server from ever exiting
+        println!("Shutting down the server; waiting for current requests to finish");
+        self.state.set_ready(false);
+        drop(self.listeners);
+        if let Err(stuck) = self.pool.shutdown_timeout(site.config.shutdown_timeout) {
+            eprintln!("Gave up waiting on workers {stuck:?}");
+        }
+        println!("Server shut down");
+    }
+
+    // Every connection that comes in, on any of the listeners, ends up here
+    fn accept(&self, mut stream: Connection, site: &Arc<Site>) {
+        // On some platforms, the stream inherits the listener's non-blocking mode, but
+        // handle_connection expects its reads to wait for the client (up to its timeout)
+        if let Err(err) = stream.set_nonblocking(false) {
+            eprintln!("Unable to handle connection: {err}");
+            return;
+        }
+
+        // If we already have as many connections as we can deal with, this one gets turned
+        // away, rather than waiting behind all of them. The 503 goes out from right here, since
+        // there's no thread free to send it, and it's small enough to not hold up the loop.
+        // Over TLS, it'd need a whole handshake first, so those connections are just closed
+        if self.connections.load(Ordering::SeqCst) >= site.config.max_connections {
+            #[cfg(feature = "tls")]
+            let plaintext = self.tls.is_none() || !matches!(stream, Connection::Tcp(_));
+            #[cfg(not(feature = "tls"))]
+            let plaintext = true;
+
+            if plaintext {
+                let response = site
+                    .router
+                    .error_pages()
+                    .render(Response::ok().status(503, "SERVICE UNAVAILABLE"))
+                    .default_headers(&site.config.default_headers)
+                    .header("Retry-After", "1")
+                    .header("Connection", "close");
+                let _ = response.write_to(&mut stream);
+            }
+            return;
+        }
+        let connection = ConnectionGuard::new(&self.connections);
+
+        // At this point, the connection has been established, so we'll take the stream
+        // and respond back appropriately to the incoming request with a valid HTTP/TCP response
+        // If the pool can't take the connection, we'll just log it and move on to the next one
+        //
+        // A connection that's been waiting for a Worker for longer than the queue timeout is
+        // just dropped (which closes it) instead, since its client has most likely given up
+        let deadline = site
+            .config
+            .queue_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let site = Arc::clone(site);
+        let state = Arc::clone(&self.state);
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let job = move || {
+            let _connection = connection;
+            if let Err(err) = serve_connection(
+                stream,
+                &site.router,
+                &site.config,
+                &state,
+                #[cfg(feature = "tls")]
+                tls.as_ref(),
+            ) {
+                // A client hanging up on us early isn't worth an error, so it's only logged
+                // when we've been asked for the details
+                if !err.is_disconnect() {
+                    eprintln!("Error handling connection: {err}");
+                } else if site.config.debug {
+                    eprintln!("Client disconnected: {err}");
+                }
+            }
+        };
+        let queued = match deadline {
+            Some(deadline) => self.pool.execute_with_deadline(job, deadline),
+            None => self.pool.execute(job),
+        };
+        if let Err(err) = queued {
+            eprintln!("Unable to handle connection: {err}");
+        }
+    }
+}
+
+// Loads the config again from source, for a new Site to serve from. A config that isn't valid
+// anymore is turned down, and we carry on with the one we've got. So is a reload without a
+// source, since making one up (i.e.: from the environment) for a config that was built in
+// code would lose whatever it was built with, like its auth
+fn reload(
+    source: Option<&ConfigSource>,
+    current: &ServerConfig,
+    parts: &RouterParts,
+) -> Option<Site> {
+    let Some(source) = source else {
+        eprintln!("There's nowhere to reload the config from, so keeping the old one");
+        return None;
+    };
+    let config = match source() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Unable to reload the config, so keeping the old one: {err}");
+            return None;
+        }
+    };
+
+    let (config, kept) = current.reload(config);
+    if !kept.is_empty() {
+        eprintln!(
+            "Changes to --{} will only take effect on a restart",
+            kept.join(", --")
+        );
+    }
+
+    println!("Reloaded the config");
+    Some(Site::new(config, parts))
+}
+
+// We're only ready for real requests if there are static files for us to serve
+fn check_ready(state: &ServerState, config: &ServerConfig) {
+    if config.static_root.is_dir() {
+        state.set_ready(true);
+    } else {
+        state.set_ready(false);
+        eprintln!(
+            "{} isn't a directory, so /readyz will say we aren't ready",
+            config.static_root.display()
+        );
+    }
+}
+
+// Everything a connection is served with. A connection holds on to the Site it started out
+// with until it's done, so reloading the config never changes anything out from under a
+// request that's in the middle of being handled
+struct Site {
+    config: ServerConfig,
+    router: Router,
+}
+
+impl Site {
+    fn new(config: ServerConfig, parts: &RouterParts) -> Site {
+        Site {
+            router: build_router(&config, parts),
+            config,
+        }
+    }
+}
+
+// The parts of the Router that outlive any one config, so they carry on across reloads
+struct RouterParts {
+    spawner: Spawner,
+    state: Arc<ServerState>,
+    metrics: Arc<Metrics>,
+    // Only /status needs these, which needs the json feature
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    connections: Arc<AtomicUsize>,
+    sessions: Arc<SessionStore>,
+    kv: Arc<KeyValueStore>,
+    access_log: Arc<LogOutput>,
+    // The routes the Server was given, on top of our own, and what to do with requests
+    // that don't match any of them
+    routes: Vec<(HttpMethod, String, Handler)>,
+    fallback: Option<Handler>,
+}
+
+// Sets up which handler each path goes to, and the middlewares around them
+fn build_router(config: &ServerConfig, parts: &RouterParts) -> Router {
+    let static_root = config.static_root.clone();
+    let autoindex = config.autoindex;
+    let mut router = Router::new(move |request| static_file(&static_root, autoindex, request));
+    router.get("/", hello);
+    router.get("/sleep", sleep);
+    router.get("/countdown", countdown);
+    router.post_streaming("/upload", upload);
+    let spawner = parts.spawner.clone();
+    router.get("/stream", move |request| stream(request, &spawner));
+
+    // Values kept in memory, by their keys, i.e.: "PUT /kv/greeting" with a body of "hello",
+    // if they've been asked for. The store itself outlives any one config, so turning it off
+    // and back on with a reload doesn't throw away what's in it
+    if config.kv_store {
+        let kv = Arc::clone(&parts.kv);
+        router.route(HttpMethod::Put, "/kv/:key", move |request| kv.put(request));
+        let kv = Arc::clone(&parts.kv);
+        router.get("/kv/:key", move |request| kv.get(request));
+        let kv = Arc::clone(&parts.kv);
+        router.route(HttpMethod::Delete, "/kv/:key", move |request| {
+            kv.delete(request)
+        });
+    }
+    router.set_trailing_slash(config.trailing_slash);
+
+    // A request that doesn't match a route, or any of the static files, gets the fallback
+    // file instead of a 404, if there is one, i.e.: a single-page app's index.html
+    if let Some(fallback) = &config.fallback {
+        let file = config.static_root.join(fallback);
+        router.fallback(move |request| fallback_file(&file, request));
+    }
+
+    // The health checks answer without going through any of the middlewares, so they
+    // don't need a password, and don't end up in the logs every few seconds
+    let healthz = Arc::clone(&parts.state);
+    router.get_unwrapped("/healthz", move |request| healthz.healthz(request));
+    let readyz = Arc::clone(&parts.state);
+    router.get_unwrapped("/readyz", move |request| readyz.readyz(request));
+
+    // Counts every request that goes through the middlewares, along with how busy the pool
+    // is. Like the health checks, they're served without the middlewares, so whatever's
+    // collecting them doesn't need to be given the password
+    let metrics = Arc::clone(&parts.metrics);
+    router.get_unwrapped(&config.metrics_path, move |request| metrics.render(request));
+
+    // How many connections are being handled (or waiting to be) right now
+    #[cfg(feature = "json")]
+    {
+        let connections = Arc::clone(&parts.connections);
+        router.get("/status", move |request| status(request, &connections));
+    }
+
+    // Error responses from any of the handlers get one of these pages, or a plain-text
+    // one, i.e.: "500 INTERNAL SERVER ERROR", for statuses without a page of their own
+    let mut error_pages = ErrorPages::new();
+    error_pages.file(404, "pages/404.html");
+    router.set_error_pages(error_pages);
+
+    // Every request that gets served is logged to stdout (or the --access-log file), laid out
+    // however --log-format says, i.e.: "%h %r %s %b %D" for
+    // "127.0.0.1:51234 GET / HTTP/1.1 200 1261 1270"
+    // The logger goes before the auth check, so requests that get turned away are logged too,
+    // and the metrics go before everything, so their timings include all the others
+    router.wrap(Arc::clone(&parts.metrics));
+    router.wrap(RequestLogger::new(
+        Arc::clone(&parts.access_log),
+        config.log_format.clone(),
+    ));
+    // Clients that send too many requests are turned away before anything else has to deal
+    // with them, which includes anyone trying password after password
+    if let Some((requests, window)) = config.rate_limit {
+        router.wrap(RateLimiter::new(requests, window));
+    }
+    // CORS goes before the auth checks, since browsers never send credentials with a preflight
+    if !config.cors.origins.is_empty() {
+        router.wrap(config.cors.clone());
+    }
+    if let Some(token) = &config.auth_token {
+        router.wrap(TokenAuth::new(token.clone()));
+    }
+    if let Some((username, password)) = &config.basic_auth {
+        router.wrap(BasicAuth::new("web-server-rust", username, password));
+    }
+    // Sessions come last, so requests that get turned away never get one
+    router.wrap(Arc::clone(&parts.sessions));
+
+    // The Server's own routes go last, so they take the place of any of ours they match
+    for (method, path, handler) in &parts.routes {
+        let handler = Arc::clone(handler);
+        router.route(*method, path, move |request| handler(request));
+    }
+    if let Some(fallback) = &parts.fallback {
+        let fallback = Arc::clone(fallback);
+        router.fallback(move |request| fallback(request));
+    }
+    router
+}
+
+// Counts a connection as open for as long as it's alive. It's dropped along with the job the
+// connection is handled by, whether that's because the job finished, it panicked, or the pool
+// wouldn't take it in the first place, so the count never drifts
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn new(connections: &Arc<AtomicUsize>) -> ConnectionGuard {
+        connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(Arc::clone(connections))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Loads the certificate and key from the config, if it has them
+#[cfg(feature = "tls")]
+fn load_tls(config: &ServerConfig) -> Result<Option<Arc<rustls::ServerConfig>>, StartError> {
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => tls::load_config(cert, key)
+            .map(Some)
+            .map_err(StartError::Tls),
+        (None, None) => Ok(None),
+        _ => Err(StartError::Config(
+            "--tls-cert and --tls-key have to be given together".to_string(),
+        )),
+    }
+}
+
+// Gets the connection ready, and then hands it over to handle_connection, wrapped in TLS
+// first if we're serving HTTPS. Connections over a Unix socket can only come from the same
+// machine, so they never need TLS
+fn serve_connection(
+    stream: Connection,
+    router: &Router,
+    config: &ServerConfig,
+    state: &ServerState,
+    #[cfg(feature = "tls")] tls: Option<&Arc<rustls::ServerConfig>>,
+) -> Result<(), ServerError> {
+    // Every read from the stream gives up after the read timeout, which parse_request reports
+    // as ParseError::TimedOut, so the client gets a 408 and the connection gets closed. This
+    // has to be set on the TcpStream itself, underneath any TLS
+    stream.set_read_timeout(Some(config.read_timeout))?;
+    stream.set_nodelay(config.tcp_nodelay)?;
+    let peer = stream.peer_addr();
+
+    #[cfg(feature = "tls")]
+    let stream = match (tls, stream) {
+        (Some(tls), Connection::Tcp(stream)) => {
+            let mut stream = tls::accept(tls, stream)?;
+            let result = handle_connection(&mut stream, peer, router, config, state);
+
+            // Let the client know we're closing the connection on purpose, so it can tell
+            // that apart from someone cutting it off partway through
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+            Connection::Tcp(stream.sock).linger_close();
+            return result;
+        }
+        (_, stream) => stream,
+    };
+
+    let mut stream = stream;
+    let result = handle_connection(&mut stream, peer, router, config, state);
+    stream.linger_close();
+    result
+}
+
+// Works the same whether the stream is a plain TcpStream, one wrapped in TLS, or anything
+// else that can be read from and written to, and have its read timeout changed (see
+// ReadTimeout), i.e.: a Unix socket, or a MemoryStream standing in for a client in a test.
+// Anything that's specific to one kind of stream (other than its read timeout) has to be set
+// up before it gets here, and its client's address (if it has one) gets passed in alongside it
+fn handle_connection<S: Read + Write + ReadTimeout>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    router: &Router,
+    config: &ServerConfig,
+    state: &ServerState,
+) -> Result<(), ServerError> {
+    // Now, WE have to assemble the request and return it back to the user/requester
+    // in the form of a valid HTTP response
+
+    // To do so, we'll let the Router pick a handler for the request, which builds up the
+    // Response (its status, headers, and body) that gets returned to the user/stream
+
+    // First, created a BufReader, so we can get a way to receive the data from the stream.
+    // The same BufReader gets used for every request on the connection, since it may have
+    // already read past the end of one request and into the start of the next. The request
+    // holds onto the BufReader until it's done with the body, so anything that has to be
+    // written before then (i.e.: a "100 Continue") goes through writer instead
+    //
+    // Both of them count the bytes that go through them, for the response's on_sent. The
+    // reader counts what's been taken out of the BufReader, rather than what it's read from
+    // the stream, since it may be holding on to the start of the next request already
+    let stream = RefCell::new(stream);
+    let (received, sent) = (Cell::new(0), Cell::new(0));
+    let mut reader = Counted::new(BufReader::new(SharedStream(&stream)), &received);
+    let mut writer = Counted::new(SharedStream(&stream), &sent);
+
+    // A client can send more than one request over the same connection, so we keep going
+    // until it's done with it, or until it has sent as many requests as we allow
+    let max_requests = config.max_requests_per_connection;
+    for served in 1..=max_requests {
+        // Once a connection's been served, it's left open for the keep-alive timeout, rather than
+        // the read timeout, for its client to send another request on. If it doesn't, we close
+        // it, without a 408, since it's not in the middle of anything. Anything it's already
+        // sent (i.e.: a request pipelined behind the last one) counts as it having sent one
+        if served > 1 {
+            stream
+                .borrow()
+                .set_read_timeout(Some(config.keep_alive_timeout))?;
+            match reader.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => {}
+                Err(err) => match ParseError::from(err) {
+                    ParseError::TimedOut => return Ok(()),
+                    err => return Err(err.into()),
+                },
+            }
+            stream
+                .borrow()
+                .set_read_timeout(Some(config.read_timeout))?;
+        }
+        received.set(0);
+        sent.set(0);
+
+        // Then, read the whole request off of it: the request line, the headers, and the body
+        // (unless its route reads the body itself). We hand the request to the Router, which
+        // picks the handler for its method and path (or the 404 handler, if none of them
+        // match), and gives us back what to respond with. If the request doesn't make sense,
+        // or uses a method we don't support, we tell the client so instead, and close the
+        // connection, since there's no telling where its next request would start
+        //
+        // A client that's waiting on a "100 Continue" before it sends the body only gets one if
+        // we'd take it. If it's too big, it gets a 413 and nothing of the body gets read, and if
+        // there's nothing to send it to, the handler's 404 (or 405) closes the connection,
+        // since the body would be in the way of the next request
+        let parsed = parse_request(&mut reader, &config.limits).and_then(|mut request| {
+            let streams_body = router.streams_body(&request.path);
+            if request.expects_continue() && request.body_reader().remaining() > 0 {
+                if !streams_body {
+                    request.check_body_size(&config.limits)?;
+                }
+                if !router.handles(request.method, &request.path) {
+                    return Ok(request);
+                }
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            }
+            if !streams_body {
+                request.read_body(&config.limits)?;
+            }
+            Ok(request)
+        });
+        let mut request = match parsed {
+            Ok(request) => request,
+            // The client hung up without sending another request (or without sending
+            // anything at all), so there's nobody to respond to
+            Err(ParseError::ConnectionClosed) => return Ok(()),
+            Err(err) => {
+                let err = ServerError::from(err);
+                if err.is_disconnect() {
+                    return Err(err);
+                }
+
+                // If the client has gone away since, this fails too, but the error
+                // we're already returning is the more useful one for working out why
+                let response = router
+                    .error_pages()
+                    .render(err.response())
+                    .default_headers(&config.default_headers)
+                    .header("Connection", "close");
+                let _ = response.write_to(&mut writer);
+                return Err(err);
+            }
+        };
+
+        request.peer = peer;
+        let peer_ip = peer.map(|peer| peer.ip());
+        request.client_ip = if config.trust_proxy {
+            request.forwarded_for().or(peer_ip)
+        } else {
+            peer_ip
+        };
+
+        // If the handler panics, the Worker that catches it says which request it was for
+        set_job_context(format!("{} {}", request.method, escape(&request.path)));
+
+        let mut keep_alive = served < max_requests && request.wants_keep_alive();
+        // Every response carries the id of the request it's for, so the client can match
+        // it up with our logs. Its status line has the same version of HTTP as the request
+        let mut response = router
+            .handle(&mut request)
+            .version(request.version)
+            .default_headers(&config.default_headers)
+            .header("X-Request-Id", &request.id);
+
+        // Compress the body for clients that can handle it. This happens for HEAD requests too,
+        // so their Content-Length matches what a GET would get
+        #[cfg(any(feature = "gzip", feature = "deflate", feature = "brotli"))]
+        {
+            response = compression::compress_if_accepted(&request, response);
+        }
+
+        // A client older than HTTP/1.1 can't take a chunked body, so a body we don't know the
+        // length of gets sent without anything marking where it ends, which means closing
+        // the connection once it's done is the only way to let the client know
+        let chunked = response.body_len().is_none();
+        let http_1_1 = request.version == HttpVersion::Http11;
+        if chunked && !http_1_1 {
+            keep_alive = false;
+        }
+
+        // A handler that reads the body itself might not have read all of it, and rather than
+        // reading the rest (which could be any size at all) just to get to the next request,
+        // we close the connection
+        if request.body_reader().remaining() > 0 {
+            keep_alive = false;
+        }
+
+        // A server that's draining finishes the request it's been sent (even one whose handler
+        // was still running when it started draining), but doesn't take any more on this
+        // connection
+        if state.is_draining() {
+            keep_alive = false;
+        }
+
+        // Let the client know whether it can send another request. HTTP/1.1 clients assume
+        // they can unless we say otherwise, but older clients have to be told
+        if !keep_alive {
+            response = response.header("Connection", "close");
+        } else if !http_1_1 {
+            response = response.header("Connection", "keep-alive");
+        }
+
+        // Lastly, we'll send the response back to the user/requester. A response to a HEAD
+        // request has the same headers a GET would have (so the same Content-Length, too),
+        // but it never has a body. Once it's been sent, whatever wanted to know how many bytes
+        // it took (i.e.: the metrics) finds out, even if the client went away partway through
+        let on_sent = response.take_on_sent();
+        let written = if request.method == HttpMethod::Head {
+            response.write_head_to(&mut writer)
+        } else if http_1_1 {
+            response.write_to(&mut writer)
+        } else {
+            response.write_unchunked_to(&mut writer)
+        };
+        on_sent(Transferred {
+            received: received.get(),
+            sent: sent.get(),
+        });
+        written?;
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// A stream that's read from and written to by turns, i.e.: by the BufReader that requests are
+// read from, and by the responses to them. Only one of them is ever using it at a time, so
+// each read or write only borrows it for as long as that read or write takes
+struct SharedStream<'s, S>(&'s RefCell<S>);
+
+impl<S: Read> Read for SharedStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+// A reader or writer that adds up how many bytes go through it, i.e.: the response's headers
+// and its body, after it's been compressed, and with the chunk sizes in between its chunks
+struct Counted<'c, T> {
+    inner: T,
+    count: &'c Cell<u64>,
+}
+
+impl<'c, T> Counted<'c, T> {
+    fn new(inner: T, count: &'c Cell<u64>) -> Counted<'c, T> {
+        Counted { inner, count }
+    }
+
+    fn add(&self, bytes: usize) {
+        self.count.set(self.count.get() + bytes as u64);
+    }
+}
+
+impl<T: Read> Read for Counted<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.add(read);
+        Ok(read)
+    }
+}
+
+// What's been read is whatever gets consumed, since fill_buf can be called on the same bytes
+// any number of times
+impl<T: BufRead> BufRead for Counted<'_, T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.add(amt);
+        self.inner.consume(amt);
+    }
+}
+
+impl<T: Write> Write for Counted<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.add(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        panic::{self, AssertUnwindSafe},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{listener::MemoryStream, logger::LogFormat};
+
+    // A Router that only has "/"
+    fn router() -> Router {
+        let mut router = Router::new(|_| Response::ok().status(404, "NOT FOUND"));
+        router.get("/", |_| Response::ok().body("hello"));
+        router
+    }
+
+    // Serves whatever the client sent, and gives back what it was sent in return
+    fn serve(input: &[u8]) -> (Result<(), ServerError>, String) {
+        serve_stream(MemoryStream::new(input))
+    }
+
+    fn serve_stream(mut stream: MemoryStream) -> (Result<(), ServerError>, String) {
+        let result = handle_connection(
+            &mut stream,
+            None,
+            &router(),
+            &ServerConfig::default(),
+            &ServerState::new(),
+        );
+        (result, String::from_utf8(stream.output).unwrap())
+    }
+
+    // Serves one request over a real TCP connection, and says whether TCP_NODELAY was set on
+    // our end of it. That's checked on a clone of the stream, which is the same socket
+    fn nodelay_after_serving(tcp_nodelay: bool) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(address).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let probe = stream.try_clone().unwrap();
+        let config = ServerConfig {
+            tcp_nodelay,
+            ..ServerConfig::default()
+        };
+        serve_connection(
+            Connection::Tcp(stream),
+            &router(),
+            &config,
+            &ServerState::new(),
+            #[cfg(feature = "tls")]
+            None,
+        )
+        .unwrap();
+
+        assert!(client.join().unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+        probe.nodelay().unwrap()
+    }
+
+    #[test]
+    fn sets_tcp_nodelay_on_the_connections_it_serves() {
+        assert!(nodelay_after_serving(true));
+        assert!(!nodelay_after_serving(false));
+    }
+
+    #[test]
+    fn answers_a_get_from_an_in_memory_client() {
+        let (result, output) =
+            serve(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+        assert!(result.is_ok());
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.split("\r\n");
+        assert_eq!(lines.next(), Some("HTTP/1.1 200 OK"));
+        let headers: Vec<&str> = lines.collect();
+        assert!(headers.contains(&"Content-Length: 5"));
+        assert!(headers.contains(&"Connection: close"));
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn answers_a_path_without_a_route_from_an_in_memory_client() {
+        let (result, output) =
+            serve(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+
+        assert!(result.is_ok());
+        assert!(output.starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+
+    #[test]
+    fn gives_up_quietly_on_a_client_that_hangs_up_partway_through_a_request() {
+        let truncated: [&[u8]; 3] = [
+            b"GET / HTTP/1.1\r\nHost: loc",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+        ];
+
+        // There's nobody left to answer, so nothing gets sent, and the error says the
+        // client went away rather than that it sent something wrong
+        for input in truncated {
+            let (result, output) = serve(input);
+            let err = result.expect_err("a truncated request shouldn't be served");
+            assert!(err.is_disconnect(), "{err:?} for {input:?}");
+            assert_eq!(output, "", "for {input:?}");
+        }
+
+        // One that never sent anything at all just didn't have a request for us
+        let (result, output) = serve(b"");
+        assert!(result.is_ok());
+        assert_eq!(output, "");
+    }
+
+    // Somewhere to log to that the test can read back afterwards
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_the_bytes_each_request_and_response_took() {
+        let log = SharedLog::default();
+        let mut router = router();
+        router.wrap(RequestLogger::new(
+            Arc::new(LogOutput::new(log.clone())),
+            LogFormat::parse("%I %O %b").unwrap(),
+        ));
+
+        // Two requests on the same connection, so each one's bytes have to be counted apart
+        let first = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let second = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let mut stream = MemoryStream::new(format!("{first}{second}").as_bytes());
+        handle_connection(
+            &mut stream,
+            None,
+            &router,
+            &ServerConfig::default(),
+            &ServerState::new(),
+        )
+        .unwrap();
+
+        let output = String::from_utf8(stream.output).unwrap();
+        let second_reply = output.rfind("HTTP/1.1 200 OK").unwrap();
+        assert!(second_reply > 0);
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            log,
+            format!(
+                "{} {} 5\n{} {} 5\n",
+                first.len(),
+                second_reply,
+                second.len(),
+                output.len() - second_reply,
+            )
+        );
+    }
+
+    #[test]
+    fn says_which_request_a_panicking_handler_was_for() {
+        let mut router = router();
+        router.get("/boom/:id", |_| panic!("the handler fell over"));
+
+        // The panic goes all the way up to the Worker, which catches it, and logs it along
+        // with whatever the job's context is by then
+        let mut stream = MemoryStream::new(b"GET /boom/42 HTTP/1.1\r\n\r\n");
+        let served = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle_connection(
+                &mut stream,
+                None,
+                &router,
+                &ServerConfig::default(),
+                &ServerState::new(),
+            )
+        }));
+        assert!(served.is_err());
+        assert_eq!(crate::take_job_context(), " (GET /boom/42)");
+        assert_eq!(crate::take_job_context(), "");
+    }
+
+    #[test]
+    fn closes_a_kept_alive_connection_without_a_408_once_the_client_is_done() {
+        // The client stays connected after its request, but never sends another one, so
+        // waiting on it for the keep-alive timeout runs out
+        let input = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (result, output) = serve_stream(MemoryStream::idle(input));
+
+        assert!(result.is_ok());
+        assert_eq!(output.matches("HTTP/1.1 ").count(), 1);
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!output.contains("408"));
+
+        // Whereas one that stops partway through a request is still in the middle of something
+        let (result, output) = serve_stream(MemoryStream::idle(b"GET / HTTP/1.1\r\nHost"));
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 408 "), "{output:?}");
+    }
+}
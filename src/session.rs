@@ -0,0 +1,186 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt::Write as _,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{CookieOptions, Response, SameSite},
+};
+
+// The cookie that the session's id is kept in
+const COOKIE_NAME: &str = "session_id";
+
+/// Keeps track of each client's session, so handlers can remember things about a client from
+/// one request to the next (i.e.: who it's logged in as) without sending any of it to the
+/// client. All the client gets is a cookie with the session's id in it, i.e.:
+/// ```text
+/// Set-Cookie: session_id=3f9c0a6e1d2b4c5a8e7f6a5b4c3d2e1f; Max-Age=1800; Path=/; HttpOnly
+/// ```
+///
+/// Sessions are only kept in memory, so they're all lost when the server restarts. One that
+/// goes unused for longer than the ttl expires, and gets thrown away
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+    ttl: Duration,
+    // Whether the cookie should only be sent back over HTTPS
+    secure: bool,
+}
+
+impl SessionStore {
+    /// A store whose sessions expire after going unused for ttl. With secure, their cookies
+    /// are only ever sent back over HTTPS
+    pub fn new(ttl: Duration, secure: bool) -> SessionStore {
+        SessionStore {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+            secure,
+        }
+    }
+
+    // Finds the session with the given id, as long as it hasn't expired, and counts this as
+    // it being used, so it lasts for another ttl from now
+    fn load(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?.clone();
+
+        let mut data = session.data.lock().unwrap();
+        if data.last_used.elapsed() > self.ttl {
+            drop(data);
+            sessions.remove(id);
+            return None;
+        }
+        data.last_used = Instant::now();
+        drop(data);
+
+        Some(session)
+    }
+
+    // Keeps a new session around for the requests after this one. This is also when any
+    // other sessions that have expired get thrown away, so there's never more of them
+    // sitting around than there have been new sessions within a ttl
+    fn save(&self, session: &Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.data.lock().unwrap().last_used.elapsed() <= self.ttl);
+        sessions.insert(session.id.clone(), session.clone());
+    }
+}
+
+// Every request gets a session, either the one its cookie is for, or a brand new one. A new
+// session is only kept (and the client only gets a cookie for it) if something gets put in
+// it, so clients that never need one (i.e.: anything just fetching static files) don't end
+// up filling the store with empty sessions
+impl Middleware for SessionStore {
+    fn handle(&self, request: &Request, next: &Next) -> Response {
+        let existing = request.cookie(COOKIE_NAME).and_then(|id| self.load(id));
+        let is_new = existing.is_none();
+        let session = existing.unwrap_or_else(Session::new);
+
+        // There's already a session if more than one SessionStore has been added to the
+        // Router, in which case the first one wins
+        let _ = request.session.set(session.clone());
+        let response = next.run(request);
+
+        if !session.take_changed() {
+            return response;
+        }
+        if is_new {
+            self.save(&session);
+        }
+
+        // Sending the cookie again whenever the session changes keeps its Max-Age in line
+        // with how long we'll keep the session around for
+        let options = CookieOptions {
+            max_age: Some(self.ttl.as_secs()),
+            path: Some("/".to_string()),
+            http_only: true,
+            secure: self.secure,
+            same_site: Some(SameSite::Lax),
+        };
+        response.set_cookie(COOKIE_NAME, &session.id, &options)
+    }
+}
+
+/// One client's session, which handlers get from request.session(). Every clone of it is the
+/// same session, so what one request puts in it, the client's next request gets back out
+#[derive(Clone)]
+pub struct Session {
+    id: String,
+    // Behind a Mutex, since a client can have more than one request in progress at once
+    // (over more than one connection), and each of them could be on a different thread
+    data: Arc<Mutex<SessionData>>,
+}
+
+struct SessionData {
+    values: HashMap<String, String>,
+    last_used: Instant,
+    // Whether anything has been put in (or taken out of) the session since we last checked
+    changed: bool,
+}
+
+impl Session {
+    fn new() -> Session {
+        Session {
+            id: new_session_id(),
+            data: Arc::new(Mutex::new(SessionData {
+                values: HashMap::new(),
+                last_used: Instant::now(),
+                changed: false,
+            })),
+        }
+    }
+
+    /// The value the session has for key, if it has one
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.data.lock().unwrap().values.get(key).cloned()
+    }
+
+    /// Sets key to value, replacing whatever the session had for it before
+    pub fn insert(&self, key: &str, value: &str) {
+        let mut data = self.data.lock().unwrap();
+        data.values.insert(key.to_string(), value.to_string());
+        data.changed = true;
+    }
+
+    /// Takes key out of the session, giving back the value it had, if it had one
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut data = self.data.lock().unwrap();
+        let removed = data.values.remove(key);
+        data.changed |= removed.is_some();
+        removed
+    }
+
+    // Whether the session has changed, resetting it for the next request
+    fn take_changed(&self) -> bool {
+        let mut data = self.data.lock().unwrap();
+        std::mem::take(&mut data.changed)
+    }
+}
+
+// Makes up an id that nobody could guess, since anyone who has a session's id can use the
+// session. There's no random number generator in the standard library, but RandomState is
+// the next best thing: each one hashes with keys that came from the operating system's
+// random number generator, so hashing something with it gives 64 bits that can't be worked
+// out without those keys. A counter and the time go into the hash, so no two ids are the same
+fn new_session_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let mut id = String::with_capacity(32);
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        hasher.write_u128(now.as_nanos());
+        let _ = write!(id, "{:016x}", hasher.finish());
+    }
+    id
+}
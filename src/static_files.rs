@@ -0,0 +1,313 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::url;
+
+use crate::{
+    autoindex::list_directory,
+    http_date::{format_http_date, parse_http_date},
+    request::Request,
+    response::Response,
+};
+
+// Files up to this size are read into memory, so they can still be compressed. Anything bigger
+// is streamed straight from the disk to the client, however big it is
+const STREAM_THRESHOLD: u64 = 256 * 1024;
+
+// Serves the file under root that the request's path points to, i.e.: "/css/site.css" under
+// "public" is "public/css/site.css". A request for a directory gets the index.html inside
+// of it, if there is one. If there isn't, and autoindex is on, it gets a page listing
+// everything in the directory instead
+//
+// The file has to actually be inside of root once any ".."s and symlinks have been
+// followed, so a request like "/../../etc/passwd" can't read anything outside of it
+//
+// A request with a Range header (i.e.: "Range: bytes=0-1023") gets just that part of
+// the file, which is what lets a download be resumed, or a video be skipped through. If it
+// comes with an If-Range (the ETag or date of the copy the client has part of), it only gets
+// the range if the file hasn't changed since, and otherwise gets the whole file again, so it
+// doesn't end up with a download stitched together from two different versions of the file
+//
+// Every file gets an ETag, which changes whenever the file does, and a Last-Modified date. A
+// client that already has a copy sends its ETag back in If-None-Match (or the date back in
+// If-Modified-Since), and if the file hasn't changed since, it gets a "304 Not Modified" with
+// no body, instead of the whole file again
+pub fn serve_static(root: &Path, request: &Request, autoindex: bool) -> Response {
+    // Both paths get canonicalized (made absolute, with every ".." and symlink resolved),
+    // which also tells us whether the file exists at all
+    let Ok(root) = root.canonicalize() else {
+        return not_found();
+    };
+    // The path can have percent-encoded characters in it, i.e.: "/my%20notes.txt", and one
+    // with a "%" that doesn't stand for anything (i.e.: "/100%zz") isn't a path at all
+    let Ok(decoded) = url::decode(request.path.trim_start_matches('/')) else {
+        return Response::ok().status(400, "BAD REQUEST");
+    };
+    let Ok(mut path) = root.join(decoded).canonicalize() else {
+        return not_found();
+    };
+
+    // Anything outside of root gets the same 404 as a file that doesn't exist at all, so
+    // there's no telling from the response which files exist outside of it
+    if !path.starts_with(&root) {
+        return not_found();
+    }
+
+    if path.is_dir() {
+        let index = path.join("index.html");
+        if autoindex && !index.exists() {
+            return match list_directory(&root, &path, &request.path) {
+                Ok(response) => response,
+                Err(err) => {
+                    eprintln!("Unable to list {}: {err}", path.display());
+                    Response::ok().status(500, "INTERNAL SERVER ERROR")
+                }
+            };
+        }
+        path = index;
+    }
+
+    serve_file(&path, request)
+}
+
+// Serves the file at path, the same way serve_static does (with its ETag, ranges, and all),
+// whatever path the request was for, i.e.: a single-page app's index.html, for a path that
+// only the app knows about. The path is used as it is, without checking where it points to
+pub fn serve_file(path: &Path, request: &Request) -> Response {
+    let response = fs::metadata(path).and_then(|metadata| {
+        let etag = etag_for(&metadata);
+        let modified = metadata.modified().ok();
+        if not_modified(request, &etag, modified) {
+            return Ok(Response::ok()
+                .status(304, "NOT MODIFIED")
+                .header("ETag", &etag));
+        }
+
+        // Read the raw bytes rather than a String, so images and other binary files work too
+        let range = request
+            .header("Range")
+            .filter(|_| if_range_matches(request, &etag, modified));
+        let response = match range {
+            Some(range) => read_range(path, range)?,
+            None => read_file(path)?,
+        };
+        let mut response = response
+            .header("Content-Type", mime_type_for(path))
+            .header("Accept-Ranges", "bytes")
+            .header("ETag", &etag);
+        if let Some(modified) = modified {
+            response = response.header("Last-Modified", &format_http_date(modified));
+        }
+        Ok(response)
+    });
+
+    match response {
+        Ok(response) => response,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => not_found(),
+        Err(err) => {
+            eprintln!("Unable to read {}: {err}", path.display());
+            Response::ok().status(500, "INTERNAL SERVER ERROR")
+        }
+    }
+}
+
+// Responds with the whole file, which is only read into memory if it's small. The length for
+// the Content-Length comes from the file's metadata, so a big file can be sent as it's read
+fn read_file(path: &Path) -> io::Result<Response> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    if len <= STREAM_THRESHOLD {
+        let mut contents = Vec::new();
+        file.take(len).read_to_end(&mut contents)?;
+        Ok(Response::ok().body(contents))
+    } else {
+        Ok(Response::ok().body_reader(file, len))
+    }
+}
+
+// Reads just the part of the file that the Range header asks for, responding with a
+// "206 Partial Content". A range we can't make sense of, or one that's past the end of
+// the file, gets a "416 Range Not Satisfiable" instead
+fn read_range(path: &Path, range: &str) -> io::Result<Response> {
+    // We only handle a single range. A client asking for several at once (which would need
+    // a multipart response) just gets the whole file, which it has to be able to handle anyway
+    if range.contains(',') {
+        return read_file(path);
+    }
+
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let Some((start, end)) = parse_range(range, len) else {
+        return Ok(Response::ok()
+            .status(416, "RANGE NOT SATISFIABLE")
+            .header("Content-Range", &format!("bytes */{len}")));
+    };
+
+    // Skip straight to the start of the range, and send only as far as its end,
+    // so we never read any more of the file than we're sending
+    file.seek(SeekFrom::Start(start))?;
+
+    Ok(Response::ok()
+        .status(206, "PARTIAL CONTENT")
+        .header("Content-Range", &format!("bytes {start}-{end}/{len}"))
+        .body_reader(file, end - start + 1))
+}
+
+// Turns a Range header into the first and last byte (inclusive) that it covers, in a file
+// that's len bytes long. The range can be in any of these forms:
+//    "bytes=500-999" -> bytes 500 through 999
+//    "bytes=500-"    -> byte 500 through the end of the file
+//    "bytes=-500"    -> the last 500 bytes of the file
+// An end that's past the end of the file just means "up to the end". Returns None if the
+// range is malformed, or doesn't cover any of the file
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let last = len.checked_sub(1)?;
+
+    if start.is_empty() {
+        let suffix = end.parse::<u64>().ok().filter(|&suffix| suffix > 0)?;
+        return Some((len.saturating_sub(suffix), last));
+    }
+
+    let start = start.parse::<u64>().ok()?;
+    let end = match end {
+        "" => last,
+        end => end.parse::<u64>().ok()?.min(last),
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+// An ETag made from the file's size and when it was last modified, i.e.: "1f4-17a3c9e2b10",
+// which is much cheaper than hashing the whole file, and changes whenever it gets written to.
+// It's a strong ETag, for the file's bytes just as they are on disk, which is what lets it be
+// used for If-Range. When the file gets sent compressed, it's made weak on the way out (see
+// compress_if_accepted), since those bytes are different
+fn etag_for(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos());
+
+    format!("\"{:x}-{modified:x}\"", metadata.len())
+}
+
+// Whether the client's copy of the file is still current. The ETag is the better way of telling,
+// so If-Modified-Since only gets looked at when there's no If-None-Match. A date we can't make
+// sense of is ignored, and the client just gets the whole file
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return etag_matches(if_none_match, etag);
+    }
+
+    let since = request
+        .header("If-Modified-Since")
+        .and_then(parse_http_date);
+    match (since, modified) {
+        // The date only goes down to the second, so the file's time has to be cut down to
+        // that too, or a file modified partway through a second would never match
+        (Some(since), Some(modified)) => {
+            seconds_since_epoch(modified) <= seconds_since_epoch(since)
+        }
+        _ => false,
+    }
+}
+
+// Whether the file is still the one the client's If-Range is for, which it always is when
+// there's no If-Range. The header is either an ETag, i.e.: "1f4-17a3c9e2b10", or a date
+//
+// If-Range only ever matches a strong ETag, so a weak one (with "W/" on the front, which is
+// what a compressed copy of the file gets) never does, and the client gets the whole file
+// instead. A date has to be exactly the file's Last-Modified, since any other date means the
+// client's copy came from some other version of the file
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(if_range) = request.header("If-Range").map(str::trim) else {
+        return true;
+    };
+
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+
+    match (parse_http_date(if_range), modified) {
+        (Some(date), Some(modified)) => seconds_since_epoch(modified) == seconds_since_epoch(date),
+        _ => false,
+    }
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// Whether etag is one of the ETags in an If-None-Match header, i.e.: "\"abc\", W/\"def\"",
+// or the header is "*", which matches anything. Whether each one is weak doesn't matter here
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+// Works out what kind of file path is from its extension, so the browser knows what to do with
+// it (i.e.: apply a stylesheet instead of showing it as text). Anything we don't recognize
+// is sent as plain binary data
+pub fn mime_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn not_found() -> Response {
+    Response::ok().status(404, "NOT FOUND")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_content_type_from_the_extension() {
+        assert_eq!(mime_type_for(Path::new("style.css")), "text/css");
+        assert_eq!(mime_type_for(Path::new("css/STYLE.CSS")), "text/css");
+        assert_eq!(mime_type_for(Path::new("index.html")), "text/html");
+        assert_eq!(mime_type_for(Path::new("app.wasm")), "application/wasm");
+        assert_eq!(
+            mime_type_for(Path::new("notes")),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            mime_type_for(Path::new("archive.tar.xz")),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// Filling in the blanks in an HTML page, i.e.: "<h1>Hello, {{name}}!</h1>" with a name of
+// "Ferris" is "<h1>Hello, Ferris!</h1>"
+//
+// Values are escaped on their way into the page, since they usually come from a client one way
+// or another, and a name like "<script>" shouldn't get to run as one. A value that's already
+// HTML (i.e.: a list put together somewhere else) goes in with three braces instead, like
+// "{{{list}}}", and is put in just as it is
+
+use std::collections::HashMap;
+
+/// Replaces each "{{name}}" in template with the value of name in vars, escaped with `escape`,
+/// and each "{{{name}}}" with the value just as it is. Spaces inside the braces are ignored,
+/// so "{{ name }}" is the same as "{{name}}"
+///
+/// A placeholder that isn't in vars is left blank, the same as a value that's empty, so a page
+/// can leave something out by just not passing it in. A "{{" that's never closed isn't a
+/// placeholder, and is left in the page as it is
+///
+/// ```
+/// use std::collections::HashMap;
+/// use web_server_rust::template;
+///
+/// let vars = HashMap::from([
+///     ("name", "<script>alert(\"hi\")</script>".to_string()),
+///     ("list", "<li>one</li>".to_string()),
+/// ]);
+/// let page = template::render("<p>{{ name }}</p><ul>{{{list}}}</ul>{{missing}}", &vars);
+/// assert_eq!(
+///     page,
+///     "<p>&lt;script&gt;alert(&quot;hi&quot;)&lt;/script&gt;</p><ul><li>one</li></ul>"
+/// );
+/// assert_eq!(template::render("{{name", &vars), "{{name");
+/// ```
+pub fn render(template: &str, vars: &HashMap<&str, String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let (open, close) = if rest.starts_with("{{{") {
+            ("{{{", "}}}")
+        } else {
+            ("{{", "}}")
+        };
+        let Some(len) = rest[open.len()..].find(close) else {
+            break;
+        };
+
+        let name = rest[open.len()..open.len() + len].trim();
+        if let Some(value) = vars.get(name) {
+            if open == "{{{" {
+                rendered.push_str(value);
+            } else {
+                rendered.push_str(&escape(value));
+            }
+        }
+        rest = &rest[open.len() + len + close.len()..];
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Makes text safe to put in an HTML page, or in one of its attributes, by writing each of
+/// the characters that mean something in HTML ("&", "<", ">", '"', and "'") as an entity
+///
+/// ```
+/// use web_server_rust::template;
+///
+/// assert_eq!(template::escape("<a href=\"x\">Tom & Jerry's</a>"),
+///     "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;");
+/// ```
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,77 @@
+use std::{error::Error, fmt, io, net::TcpStream, path::Path, sync::Arc, time::Duration};
+
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::listener::ReadTimeout;
+
+// A connection that's been wrapped in TLS. Reading from it gives back what the client sent,
+// already decrypted, and anything written to it gets encrypted before it goes out, so the
+// rest of the server can treat it just like a plain TcpStream
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+// The timeout goes on the TcpStream underneath, since that's what's actually being read from
+impl ReadTimeout for TlsStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+// Sets up TLS with the certificate (along with the rest of its chain, if it has one) and the
+// private key that goes with it, both in PEM files, i.e.: the fullchain.pem and privkey.pem
+// that Let's Encrypt hands out
+pub fn load_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| TlsError::Certificate(err.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::Certificate(format!(
+            "no certificates found in {}",
+            cert_path.display()
+        )));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|err| TlsError::PrivateKey(err.to_string()))?;
+
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|err| TlsError::Config(err.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+// Wraps the connection in TLS. The handshake doesn't happen until the first read or write,
+// which is on whichever thread in the pool handles the connection, so a slow client can't
+// hold up the accept loop
+pub fn accept(config: &Arc<ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+    let connection = ServerConnection::new(Arc::clone(config)).map_err(io::Error::other)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+// The ways setting up TLS can go wrong. Each one has a description of what went wrong
+#[derive(Debug)]
+pub enum TlsError {
+    // The certificate file couldn't be read, or didn't have any certificates in it
+    Certificate(String),
+    // The private key file couldn't be read, or didn't have a key in it
+    PrivateKey(String),
+    // The certificate and key were read, but can't be used, i.e.: they don't go together
+    Config(String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Certificate(err) => write!(f, "invalid TLS certificate: {err}"),
+            TlsError::PrivateKey(err) => write!(f, "invalid TLS private key: {err}"),
+            TlsError::Config(err) => write!(f, "invalid TLS configuration: {err}"),
+        }
+    }
+}
+
+impl Error for TlsError {}
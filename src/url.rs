@@ -0,0 +1,131 @@
+// Percent-encoding, the way URLs escape any bytes that would otherwise mean something else
+// in them, i.e.: "my notes.txt" is "my%20notes.txt" in a path
+//
+// A path and a form (or a query string) are decoded almost the same way. The only difference
+// is "+", which browsers use for a space in a form, but which is just a "+" in a path, so
+// each one has its own function
+
+use std::{error::Error, fmt};
+
+/// The ways percent-encoded text can fail to decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A "%" at this byte offset isn't followed by two hex digits, i.e.: "%zz", or a "%"
+    /// right at the end
+    InvalidEscape(usize),
+    /// The bytes that came out of it aren't valid UTF-8
+    InvalidUtf8,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::InvalidEscape(at) => write!(f, "invalid percent escape at byte {at}"),
+            DecodeError::InvalidUtf8 => write!(f, "the decoded bytes aren't valid UTF-8"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Turns each "%XX" in a path back into the byte it stands for. A "+" is left as a "+"
+///
+/// ```
+/// use web_server_rust::url::{self, DecodeError};
+///
+/// assert_eq!(url::decode("my%20notes+draft.txt"), Ok("my notes+draft.txt".to_string()));
+/// assert_eq!(url::decode("caf%C3%A9"), Ok("café".to_string()));
+/// assert_eq!(url::decode("100%zz"), Err(DecodeError::InvalidEscape(3)));
+/// assert_eq!(url::decode("50%"), Err(DecodeError::InvalidEscape(2)));
+/// assert_eq!(url::decode("%FF"), Err(DecodeError::InvalidUtf8));
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if a "%" isn't followed by two hex digits, or if what it decodes to
+/// isn't valid UTF-8
+pub fn decode(encoded: &str) -> Result<String, DecodeError> {
+    decode_with(encoded, false)
+}
+
+/// The same as `decode`, but for a form or a query string, where a "+" is a space
+///
+/// ```
+/// use web_server_rust::url;
+///
+/// assert_eq!(url::decode_form("q=rust+lang"), Ok("q=rust lang".to_string()));
+/// assert_eq!(url::decode_form("1%2B1"), Ok("1+1".to_string()));
+/// ```
+///
+/// # Errors
+///
+/// The same as `decode`
+pub fn decode_form(encoded: &str) -> Result<String, DecodeError> {
+    decode_with(encoded, true)
+}
+
+/// Writes every byte that isn't a letter, a digit, or one of "-._~" as a "%XX". That's
+/// everything in the reserved set (i.e.: "/", "?", "&", and "+"), so the result is safe to
+/// put anywhere in a URL, and decodes back the same with either `decode` or `decode_form`
+///
+/// ```
+/// use web_server_rust::url;
+///
+/// let text = "a/b c+d&e=é";
+/// let encoded = url::encode(text);
+/// assert_eq!(encoded, "a%2Fb%20c%2Bd%26e%3D%C3%A9");
+/// assert_eq!(url::decode(&encoded).as_deref(), Ok(text));
+/// assert_eq!(url::decode_form(&encoded).as_deref(), Ok(text));
+/// ```
+pub fn encode(text: &str) -> String {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(char::from(byte));
+            }
+            byte => {
+                encoded.push('%');
+                encoded.push(char::from(HEX[usize::from(byte >> 4)]));
+                encoded.push(char::from(HEX[usize::from(byte & 0xF)]));
+            }
+        }
+    }
+    encoded
+}
+
+fn decode_with(encoded: &str, plus_is_space: bool) -> Result<String, DecodeError> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_is_space => decoded.push(b' '),
+            b'%' => {
+                let high = bytes.get(i + 1).and_then(|&digit| hex_value(digit));
+                let low = bytes.get(i + 2).and_then(|&digit| hex_value(digit));
+                let (Some(high), Some(low)) = (high, low) else {
+                    return Err(DecodeError::InvalidEscape(i));
+                };
+                decoded.push(high << 4 | low);
+                i += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8(decoded).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}